@@ -0,0 +1,168 @@
+//! Measures interpreter throughput in instructions per second.
+//!
+//! Run with `cargo bench --bench interpreter`. The `decoding` and `decoded` rows run the
+//! same programs through a minimal interpreter, once decoding each word and running the ALU
+//! from its control bits as it goes, as `Machine` did before it decoded the ROM at load
+//! time, and once with the ROM decoded up front and the ALU's shortcuts. They isolate what
+//! decoding at load time is worth from the rest of `Machine`'s work.
+
+use std::time::{Duration, Instant};
+
+use hack_rs::{
+    asm::compile_file,
+    asm::compile_lines,
+    hackword::HackWord,
+    instruction::Instruction,
+    machine::{Machine, MEMORY_SIZE},
+};
+
+/// Counts R0 down from 2000 to 0, counting R1 down from 30000 each time round
+const COUNTDOWN: &str = "
+    @2000
+    D=A
+    @R0
+    M=D
+(OUTER)
+    @30000
+    D=A
+    @R1
+    M=D
+(INNER)
+    @R1
+    MD=M-1
+    @INNER
+    D;JGT
+    @R0
+    MD=M-1
+    @OUTER
+    D;JGT
+";
+
+const FILL_STEPS: u64 = 200_000_000;
+
+fn report(name: &str, steps: u64, elapsed: Duration) {
+    let ips = steps as f64 / elapsed.as_secs_f64();
+    println!(
+        "{name:<14} {steps:>12} instructions in {:>8.3}s: {:>8.2}M instructions/s",
+        elapsed.as_secs_f64(),
+        ips / 1_000_000.0
+    );
+}
+
+/// Runs `rom` for at most `max_steps` instructions, returning how many ran. With `DECODED`
+/// the ROM is decoded before it starts, otherwise each word is decoded as it runs.
+fn interpret<const DECODED: bool>(rom: &[HackWord], ram: &mut [HackWord], max_steps: u64) -> u64 {
+    let decoded: Vec<Instruction> = match DECODED {
+        true => rom.iter().map(|&word| word.into()).collect(),
+        false => Vec::new(),
+    };
+    let (mut pc, mut a, mut d) = (0, HackWord::zero(), HackWord::zero());
+    let mut steps = 0;
+    while steps < max_steps && pc < rom.len() {
+        let instruction = match DECODED {
+            true => decoded[pc],
+            false => Instruction::from(rom[pc]),
+        };
+        match instruction {
+            Instruction::A(value) => {
+                a = HackWord(value as i16);
+                pc += 1;
+            }
+            Instruction::C {
+                comp,
+                should_deref,
+                dest,
+                jump,
+            } => {
+                let address = a.to_usize() % MEMORY_SIZE;
+                let y = if should_deref { ram[address] } else { a };
+                let out = match DECODED {
+                    true => comp.compute(d, y),
+                    false => comp.compute_bits(d, y),
+                };
+                if dest.a {
+                    a = out;
+                }
+                if dest.d {
+                    d = out;
+                }
+                if dest.m {
+                    ram[address] = out;
+                }
+                pc = if jump.should_jump(out) {
+                    address
+                } else {
+                    pc + 1
+                };
+            }
+        }
+        steps += 1;
+    }
+    steps
+}
+
+fn minimal<const DECODED: bool>(name: &str, rom: &[HackWord], keyboard: i16, max_steps: u64) {
+    let mut ram = vec![HackWord::zero(); MEMORY_SIZE];
+    ram[hack_rs::io::KB_MEM_SLOT as usize] = HackWord(keyboard);
+
+    let start = Instant::now();
+    let steps = interpret::<DECODED>(rom, &mut ram, max_steps);
+    report(name, steps, start.elapsed());
+}
+
+fn countdown() {
+    let mut machine = Machine::from_instructions(compile_lines(COUNTDOWN).unwrap());
+
+    let start = Instant::now();
+    let mut steps = 0;
+    while machine.step() {
+        steps += 1;
+    }
+    report("countdown", steps, start.elapsed());
+
+    assert_eq!(machine.memory[0], HackWord::zero());
+}
+
+fn countdown_threaded() {
+    let mut machine = Machine::from_instructions(compile_lines(COUNTDOWN).unwrap());
+    // the block engine doesn't count steps, so count them from a plain run first
+    let steps = {
+        let mut counter = Machine::from_instructions(compile_lines(COUNTDOWN).unwrap());
+        let mut steps = 0;
+        while counter.step() {
+            steps += 1;
+        }
+        steps
+    };
+
+    let start = Instant::now();
+    machine.run_threaded();
+    report("threaded", steps, start.elapsed());
+
+    assert_eq!(machine.memory[0], HackWord::zero());
+}
+
+fn fill() {
+    let (instructions, _) = compile_file("resources/fill.asm", false).unwrap();
+    let mut machine = Machine::from_instructions(instructions);
+    machine.memory[hack_rs::io::KB_MEM_SLOT as usize] = HackWord(b'A'.into());
+
+    let start = Instant::now();
+    for _ in 0..FILL_STEPS {
+        machine.step();
+    }
+    report("fill", FILL_STEPS, start.elapsed());
+}
+
+fn main() {
+    let countdown_rom = compile_lines(COUNTDOWN).unwrap();
+    minimal::<false>("decoding", &countdown_rom, 0, u64::MAX);
+    minimal::<true>("decoded", &countdown_rom, 0, u64::MAX);
+    countdown();
+    countdown_threaded();
+
+    let (fill_rom, _) = compile_file("resources/fill.asm", false).unwrap();
+    minimal::<false>("fill decoding", &fill_rom, b'A'.into(), FILL_STEPS);
+    minimal::<true>("fill decoded", &fill_rom, b'A'.into(), FILL_STEPS);
+    fill();
+}
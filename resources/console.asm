@@ -0,0 +1,118 @@
+// Console output routines, for programs run with `--console`. Append this file to a
+// program to use them.
+//
+// Printing a single character just takes writing its code to the console, which the
+// assembler's CONSOLE symbol puts at its default address, 24577:
+//     @65
+//     D=A
+//     @CONSOLE
+//     M=D    // prints A
+//
+// CONSOLE is always 24577, so to run with --console=ADDR, replace each @CONSOLE here and
+// in the program with @ADDR.
+//
+// PRINT_INT prints RAM[R13] in decimal, for values from -32767 to 32767. Put the value
+// in R13 and the address to return to in R14, then jump to PRINT_INT:
+//     @R0
+//     D=M
+//     @R13
+//     M=D
+//     @RETURN
+//     D=A
+//     @R14
+//     M=D
+//     @PRINT_INT
+//     0;JMP
+// (RETURN)
+// It overwrites D, R13 and R15.
+
+(PRINT_INT)
+    @R13
+    D=M
+    @PRINT_INT_POSITIVE
+    D;JGE
+    @45     // '-'
+    D=A
+    @CONSOLE
+    M=D
+    @R13
+    M=-M
+(PRINT_INT_POSITIVE)
+    @10000
+    D=A
+    @print_int.power
+    M=D
+    @print_int.started
+    M=0
+
+// take the current power of ten away from R13 as often as it fits
+(PRINT_INT_DIGIT)
+    @R15
+    M=0
+(PRINT_INT_SUBTRACT)
+    @print_int.power
+    D=M
+    @R13
+    D=M-D
+    @PRINT_INT_WRITE
+    D;JLT
+    @R13
+    M=D
+    @R15
+    M=M+1
+    @PRINT_INT_SUBTRACT
+    0;JMP
+
+// print the digit, unless it is a leading zero
+(PRINT_INT_WRITE)
+    @R15
+    D=M
+    @print_int.started
+    D=D|M
+    @PRINT_INT_EMIT
+    D;JNE
+    @print_int.power
+    D=M-1
+    @PRINT_INT_NEXT
+    D;JNE
+(PRINT_INT_EMIT)
+    @print_int.started
+    M=-1
+    @R15
+    D=M
+    @48     // '0'
+    D=D+A
+    @CONSOLE
+    M=D
+
+// divide the power by ten, also by subtraction, and stop after the units
+(PRINT_INT_NEXT)
+    @print_int.power
+    D=M-1
+    @PRINT_INT_RETURN
+    D;JEQ
+    @R15
+    M=0
+(PRINT_INT_DIVIDE)
+    @10
+    D=A
+    @print_int.power
+    MD=M-D
+    @PRINT_INT_DIVIDED
+    D;JLT
+    @R15
+    M=M+1
+    @PRINT_INT_DIVIDE
+    0;JMP
+(PRINT_INT_DIVIDED)
+    @R15
+    D=M
+    @print_int.power
+    M=D
+    @PRINT_INT_DIGIT
+    0;JMP
+
+(PRINT_INT_RETURN)
+    @R14
+    A=M
+    0;JMP
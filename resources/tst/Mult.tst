@@ -0,0 +1,43 @@
+// Tests mult.asm, which multiplies R0 and R1 into R2. It uses R1 as its loop counter, so
+// the compare file only checks R0 and the product.
+
+load ../mult.asm,
+output-file Mult.out,
+compare-to Mult.cmp,
+output-list RAM[0]%D2.6.2 RAM[1]%D2.6.2 RAM[2]%D2.6.2;
+
+set RAM[0] 0, set RAM[1] 0, set RAM[2] 0;
+repeat 20 {
+  ticktock;
+}
+output;
+
+set PC 0, set RAM[0] 1, set RAM[1] 0, set RAM[2] 0;
+repeat 50 {
+  ticktock;
+}
+output;
+
+set PC 0, set RAM[0] 0, set RAM[1] 2, set RAM[2] 0;
+repeat 80 {
+  ticktock;
+}
+output;
+
+set PC 0, set RAM[0] 3, set RAM[1] 1, set RAM[2] 0;
+repeat 120 {
+  ticktock;
+}
+output;
+
+set PC 0, set RAM[0] 2, set RAM[1] 4, set RAM[2] 0;
+repeat 150 {
+  ticktock;
+}
+output;
+
+set PC 0, set RAM[0] 6, set RAM[1] 7, set RAM[2] 0;
+repeat 210 {
+  ticktock;
+}
+output;
@@ -1,8 +1,9 @@
-use std::{collections::HashMap, path::Path, str::FromStr};
+use std::{collections::{BTreeMap, HashMap}, path::Path, str::FromStr};
 
 use crate::{
     common::{read_lines, Error, Res},
     hack::{
+        devices::CONSOLE_ADDRESS,
         hackword::HackWord,
         instruction::{Comp, Dest, Instruction, Jump},
         io::{KB_MEM_SLOT, SCREEN_MEM_START},
@@ -46,7 +47,7 @@ impl FromStr for AsmLine {
         let line = line.trim();
 
         Ok(AsmLine {
-            comment: if comment == "" {
+            comment: if comment.is_empty() {
                 None
             } else {
                 Some(comment.into())
@@ -92,68 +93,7 @@ impl FromStr for AsmLine {
                     }
                 }
 
-                let mut should_deref = false;
-                let comp = match comp {
-                    "0" => Comp::Zero,
-                    "1" => Comp::One,
-                    "-1" => Comp::MinusOne,
-                    "D" => Comp::D,
-                    "A" => Comp::A,
-                    "M" => {
-                        should_deref = true;
-                        Comp::A
-                    }
-                    "!D" => Comp::NotD,
-                    "!A" => Comp::NotA,
-                    "!M" => {
-                        should_deref = true;
-                        Comp::NotA
-                    }
-                    "-D" => Comp::MinusD,
-                    "-A" => Comp::MinusA,
-                    "-M" => {
-                        should_deref = true;
-                        Comp::MinusA
-                    }
-                    "D+1" => Comp::DPlus1,
-                    "A+1" => Comp::APlus1,
-                    "M+1" => {
-                        should_deref = true;
-                        Comp::APlus1
-                    }
-                    "D-1" => Comp::DMinus1,
-                    "A-1" => Comp::AMinus1,
-                    "M-1" => {
-                        should_deref = true;
-                        Comp::AMinus1
-                    }
-                    "D+A" => Comp::DPlusA,
-                    "D+M" => {
-                        should_deref = true;
-                        Comp::DPlusA
-                    }
-                    "D-A" => Comp::DMinusA,
-                    "D-M" => {
-                        should_deref = true;
-                        Comp::DMinusA
-                    }
-                    "A-D" => Comp::AMinusD,
-                    "M-D" => {
-                        should_deref = true;
-                        Comp::AMinusD
-                    }
-                    "D&A" => Comp::DAndA,
-                    "D&M" => {
-                        should_deref = true;
-                        Comp::DAndA
-                    }
-                    "D|A" => Comp::DOrA,
-                    "D|M" => {
-                        should_deref = true;
-                        Comp::DOrA
-                    }
-                    _ => return Err(format!("Unrecognised comp '{comp}'").into()),
-                };
+                let (comp, should_deref) = Comp::parse_asm(comp)?;
 
                 let jump = match jump {
                     None => Jump::Null,
@@ -207,6 +147,7 @@ pub fn compile(asm_lines: Vec<String>, debug: bool) -> Res<(Vec<HackWord>, Optio
         ("THAT".into(), 4),
         ("SCREEN".into(), SCREEN_MEM_START),
         ("KBD".into(), KB_MEM_SLOT),
+        ("CONSOLE".into(), CONSOLE_ADDRESS),
     ]);
 
     let mut hashwords = Vec::new();
@@ -218,11 +159,13 @@ pub fn compile(asm_lines: Vec<String>, debug: bool) -> Res<(Vec<HackWord>, Optio
     };
 
     // first pass: load labels into memory
+    let mut labels = HashMap::new();
     let mut i = 0;
     for asm in &parsed {
         match &asm.instruction {
             Asm::Label(l) => {
                 symbols.insert(l.into(), i);
+                labels.insert(l.clone(), i);
             }
             Asm::EmptyLine => (),
             _ => {
@@ -277,6 +220,7 @@ pub fn compile(asm_lines: Vec<String>, debug: bool) -> Res<(Vec<HackWord>, Optio
     let debug_info = if debug {
         Some(AsmDebug {
             symbols,
+            labels,
             line_mappings,
         })
     } else {
@@ -286,14 +230,88 @@ pub fn compile(asm_lines: Vec<String>, debug: bool) -> Res<(Vec<HackWord>, Optio
     Ok((hashwords, debug_info))
 }
 
+#[derive(Clone, Debug, Default)]
 pub struct AsmDebug {
     pub symbols: HashMap<String, u16>,
+    /// The subset of `symbols` declared as `(LABEL)`, mapped to ROM addresses
+    pub labels: HashMap<String, u16>,
+    /// ROM address to 1-based source line number and text
     pub line_mappings: HashMap<usize, (usize, String)>,
 }
 
+impl AsmDebug {
+    pub fn label_address(&self, label: &str) -> Option<u16> {
+        self.labels.get(label).copied()
+    }
+
+    /// The ROM address of the first instruction on or after a 1-based source line
+    pub fn line_address(&self, line: usize) -> Option<u16> {
+        self.line_mappings
+            .iter()
+            .filter(|(_, (l, _))| *l >= line)
+            .map(|(&address, _)| address as u16)
+            .min()
+    }
+
+    /// RAM addresses and the variables stored at them, each address's names sorted
+    pub fn variables(&self) -> BTreeMap<u16, Vec<String>> {
+        let mut variables: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+        for (name, &address) in &self.symbols {
+            if !self.labels.contains_key(name) {
+                variables.entry(address).or_default().push(name.clone());
+            }
+        }
+        variables.values_mut().for_each(|names| names.sort());
+        variables
+    }
+}
+
+/// Per-address lookups into [`AsmDebug`], for tools that look up every executed instruction
+#[derive(Clone, Debug, Default)]
+pub struct SourceIndex {
+    /// Labels sorted by ROM address
+    labels: Vec<(u16, String)>,
+    lines: Vec<Option<(usize, String)>>,
+}
+
+impl SourceIndex {
+    pub fn new(debug: Option<&AsmDebug>) -> Self {
+        let mut index = Self::default();
+        if let Some(debug) = debug {
+            index.labels = debug
+                .labels
+                .iter()
+                .map(|(name, &pc)| (pc, name.clone()))
+                .collect();
+            index.labels.sort();
+            for (&pc, (line, text)) in &debug.line_mappings {
+                if index.lines.len() <= pc {
+                    index.lines.resize(pc + 1, None);
+                }
+                index.lines[pc] = Some((*line, text.trim().to_string()));
+            }
+        }
+        index
+    }
+
+    /// The 1-based source line of the instruction at `pc`, and its text
+    pub fn line(&self, pc: u16) -> Option<(usize, &str)> {
+        let (line, text) = self.lines.get(pc as usize)?.as_ref()?;
+        Some((*line, text))
+    }
+
+    /// The last label at or before `pc`, and how far past it `pc` is
+    pub fn label(&self, pc: u16) -> Option<(&str, u16)> {
+        let index = self.labels.partition_point(|(address, _)| *address <= pc);
+        let (address, label) = &self.labels[index.checked_sub(1)?];
+        Some((label, pc - address))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn string_to_asm() {
@@ -327,6 +345,19 @@ mod tests {
                     jump: Jump::JGT,
                 },
             ),
+            (
+                "AM=%1000001",
+                Asm::Compute {
+                    dest: Dest {
+                        a: true,
+                        d: false,
+                        m: true,
+                    },
+                    should_deref: true,
+                    comp: Comp::from_bits(0b000001),
+                    jump: Jump::Null,
+                },
+            ),
         ] {
             let res: AsmLine = input.parse().unwrap();
 
@@ -346,7 +377,7 @@ mod tests {
             let (instructions, _) = compile_file("resources/mult.asm", false).unwrap();
             machine.load_instructions(instructions);
 
-            machine.run().unwrap();
+            machine.run();
 
             assert_eq!(machine.memory[2], HackWord(a * b))
         }
@@ -407,4 +438,26 @@ mod tests {
 
         assert_eq!(res, expected);
     }
+
+    proptest! {
+        #[test]
+        fn a_instructions_round_trip(value in 0..=32767u16) {
+            let res: AsmLine = Instruction::A(value).to_string().parse().unwrap();
+            prop_assert_eq!(res.instruction, Asm::LoadAddress(MemoryLocation::Numeric(value)));
+        }
+
+        #[test]
+        fn c_instructions_round_trip(bits: u16) {
+            // C-instructions always have their two unused bits set
+            let word = HackWord::from_u16(bits | 0xE000);
+            let Instruction::C { comp, should_deref, dest, jump } = Instruction::from(word) else {
+                unreachable!()
+            };
+            let asm = Instruction::from(word).to_string();
+
+            let res: AsmLine = asm.parse().unwrap();
+            prop_assert_eq!(res.instruction, Asm::Compute { dest, should_deref, comp, jump });
+            prop_assert_eq!(compile_lines(&asm).unwrap(), vec![word]);
+        }
+    }
 }
@@ -14,3 +14,29 @@ pub fn read_lines(path: impl AsRef<Path>) -> Res<Vec<String>> {
         .map(|x| x.map_err(|e| e.into()));
     lines.collect()
 }
+
+/// A fresh directory for a test's files, removed when it's dropped
+#[cfg(test)]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Makes an empty directory named after `name` and the test process
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("hack-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
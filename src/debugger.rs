@@ -0,0 +1,514 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+use crate::{
+    asm::AsmDebug,
+    common::{err, Res},
+    hackword::HackWord,
+    instruction::Instruction,
+    machine::{Machine, StopReason, WatchKind, Watchpoint, MEMORY_SIZE},
+};
+
+/// How many instructions `continue` and `next` run before giving control back
+const RUN_LIMIT: u64 = 100_000_000;
+/// The most memory the undo log for reverse stepping may use
+const HISTORY_BUDGET: usize = 64 << 20;
+/// Lines shown either side of the current one by `list`
+const LIST_CONTEXT: usize = 5;
+
+const HELP: &str = "\
+step [n]         execute n instructions (default 1)
+next             run until the instruction after this one, stepping over jumps
+continue         run until a breakpoint, watchpoint or the program ends
+reverse-step [n] undo n instructions (default 1)
+reverse-continue run backwards to a breakpoint or a write to a watched address
+goto <cycle>     run forwards or backwards to a cycle number
+break <loc>      set a breakpoint at a label, source line or *ROM address
+delete <loc>     remove a breakpoint
+watch <addr>     stop when an address (or range a..b) is written
+rwatch <addr>    stop when an address is read
+awatch <addr>    stop when an address is read or written
+print <expr>     show A, D, M, PC, a variable or RAM[n]
+x/<n> <addr>     examine n words of RAM from a variable or address
+info registers   show A, D, M, PC and the cycle number
+info breakpoints show breakpoints and watchpoints
+list [line]      show the source around the current (or given) line
+quit             exit the debugger
+Pressing enter repeats the last command.";
+
+/// An interactive command-line debugger, driving a [`Machine`] from text commands
+pub struct Debugger {
+    machine: Machine,
+    debug: Option<AsmDebug>,
+    source: Vec<String>,
+    /// RAM addresses and the variables stored at them
+    ram_names: BTreeMap<u16, Vec<String>>,
+}
+
+impl Debugger {
+    /// `source` is the asm the machine's program was compiled from, if there is any
+    pub fn new(mut machine: Machine, debug: Option<AsmDebug>, source: Vec<String>) -> Self {
+        let ram_names = debug.as_ref().map(AsmDebug::variables).unwrap_or_default();
+
+        if !machine.is_recording() {
+            machine.record_history(HISTORY_BUDGET);
+        }
+
+        Self {
+            machine,
+            debug,
+            source,
+            ram_names,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// Reads commands from `input` until it ends or the user quits
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write) -> Res {
+        self.show_location(&mut output)?;
+        let mut last = String::new();
+        prompt(&mut output)?;
+        for line in input.lines() {
+            let line = line?;
+            let command = match line.trim() {
+                "" => last.clone(),
+                command => command.to_string(),
+            };
+            if !command.is_empty() {
+                match self.command(&command, &mut output) {
+                    Ok(false) => return Ok(()),
+                    Ok(true) => (),
+                    Err(e) => writeln!(output, "{e}")?,
+                }
+            }
+            last = command;
+            prompt(&mut output)?;
+        }
+        Ok(())
+    }
+
+    /// Runs a single command, returning `false` if the debugger should exit
+    pub fn command(&mut self, command: &str, out: &mut impl Write) -> Res<bool> {
+        let (name, arg) = command
+            .split_once(char::is_whitespace)
+            .map(|(name, arg)| (name, arg.trim()))
+            .unwrap_or((command, ""));
+
+        match name {
+            "step" | "s" => {
+                let count = if arg.is_empty() { 1 } else { arg.parse()? };
+                let reason = self.machine.run_until_break(count);
+                self.report(reason, out)?;
+            }
+            "next" | "n" => {
+                let after = self.machine.pc().wrapping_add(1);
+                let temporary = !self.machine.breakpoints().any(|pc| pc == after);
+                self.machine.add_breakpoint(after);
+                let reason = self.machine.run_until_break(RUN_LIMIT);
+                if temporary {
+                    self.machine.remove_breakpoint(after);
+                }
+                match reason {
+                    StopReason::Breakpoint(pc) if temporary && pc == after => {
+                        self.show_location(out)?
+                    }
+                    reason => self.report(reason, out)?,
+                }
+            }
+            "continue" | "c" => {
+                let reason = self.machine.run_until_break(RUN_LIMIT);
+                self.report(reason, out)?;
+            }
+            "reverse-step" | "rs" => {
+                let count: u64 = if arg.is_empty() { 1 } else { arg.parse()? };
+                if (0..count).all(|_| self.machine.step_back()) {
+                    self.show_location(out)?;
+                } else {
+                    self.report(StopReason::HistoryStart, out)?;
+                }
+            }
+            "reverse-continue" | "rc" => {
+                let reason = self.machine.reverse_continue();
+                self.report(reason, out)?;
+            }
+            "goto" => {
+                self.machine.goto_cycle(arg.parse()?)?;
+                self.show_location(out)?;
+            }
+            "break" | "b" => {
+                let pc = self.location(arg)?;
+                self.machine.add_breakpoint(pc);
+                writeln!(out, "Breakpoint at {}", self.describe_rom(pc))?;
+            }
+            "delete" | "d" => {
+                let pc = self.location(arg)?;
+                if !self.machine.remove_breakpoint(pc) {
+                    return Err(format!("No breakpoint at {}", self.describe_rom(pc)).into());
+                }
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match name {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::ReadWrite,
+                };
+                let range = match arg.split_once("..") {
+                    Some((start, end)) => self.address(start)?..=self.address(end)?,
+                    None => {
+                        let address = self.address(arg)?;
+                        address..=address
+                    }
+                };
+                writeln!(out, "Watching {}", self.describe_range(&range))?;
+                self.machine.add_watchpoint(Watchpoint::range(range, kind));
+            }
+            "print" | "p" => {
+                let (label, value) = self.evaluate(arg)?;
+                writeln!(out, "{label} = {}", value.describe())?;
+            }
+            "info" | "i" => match arg {
+                "registers" | "r" => self.show_registers(out)?,
+                "breakpoints" | "b" => self.show_breakpoints(out)?,
+                _ => return Err(err("Usage: info registers|breakpoints")),
+            },
+            "list" | "l" => {
+                let line = if arg.is_empty() {
+                    None
+                } else {
+                    Some(arg.parse()?)
+                };
+                self.list(line, out)?;
+            }
+            "help" | "h" => writeln!(out, "{HELP}")?,
+            "quit" | "q" => return Ok(false),
+            _ => match name.strip_prefix("x/").or((name == "x").then_some("1")) {
+                Some(count) => {
+                    let count: u16 = count.parse()?;
+                    let start = self.address(arg)?;
+                    for address in (start..).take(count as usize) {
+                        let address = address % MEMORY_SIZE as u16;
+                        writeln!(
+                            out,
+                            "{} = {}",
+                            self.describe_ram(address),
+                            self.machine.memory[address as usize].describe()
+                        )?;
+                    }
+                }
+                None => return Err(format!("Unknown command '{name}', try 'help'").into()),
+            },
+        }
+        Ok(true)
+    }
+
+    fn report(&self, reason: StopReason, out: &mut impl Write) -> Res {
+        match reason {
+            StopReason::Breakpoint(pc) => writeln!(out, "Breakpoint at {}", self.describe_rom(pc))?,
+            StopReason::Watchpoint {
+                address,
+                kind: WatchKind::Read,
+                new,
+                ..
+            } => writeln!(
+                out,
+                "Watchpoint: read {} = {}",
+                self.describe_ram(address),
+                new.describe()
+            )?,
+            StopReason::Watchpoint {
+                address, old, new, ..
+            } => writeln!(
+                out,
+                "Watchpoint: wrote {}: {} -> {}",
+                self.describe_ram(address),
+                old.describe(),
+                new.describe()
+            )?,
+            StopReason::Halted => {
+                writeln!(out, "Program halted")?;
+                return Ok(());
+            }
+            StopReason::StepLimit => (),
+            StopReason::HistoryStart => writeln!(out, "Reached the oldest recorded step")?,
+        }
+        self.show_location(out)
+    }
+
+    fn show_location(&self, out: &mut impl Write) -> Res {
+        let pc = self.machine.pc();
+        match self.instruction(pc) {
+            Some(text) => writeln!(out, "{}: {}", self.describe_rom(pc), text)?,
+            None => writeln!(out, "{}: end of program", self.describe_rom(pc))?,
+        }
+        Ok(())
+    }
+
+    fn show_registers(&self, out: &mut impl Write) -> Res {
+        let a = self.machine.register_a();
+        writeln!(out, "A  = {}", a.describe())?;
+        writeln!(out, "D  = {}", self.machine.register_d().describe())?;
+        writeln!(
+            out,
+            "M  = {}",
+            self.machine.memory[a.to_usize() % MEMORY_SIZE].describe()
+        )?;
+        writeln!(out, "PC = {}", self.machine.pc())?;
+        writeln!(out, "cycle {}", self.machine.cycles())?;
+        Ok(())
+    }
+
+    fn show_breakpoints(&self, out: &mut impl Write) -> Res {
+        for pc in self.machine.breakpoints() {
+            writeln!(out, "break {}", self.describe_rom(pc))?;
+        }
+        for watchpoint in self.machine.watchpoints() {
+            let name = match watchpoint.kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::ReadWrite => "awatch",
+            };
+            writeln!(out, "{name} {}", self.describe_range(&watchpoint.range))?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, line: Option<usize>, out: &mut impl Write) -> Res {
+        let pc = self.machine.pc();
+        if self.source.is_empty() {
+            // no source: disassemble the ROM instead
+            let pc = pc as usize;
+            let start = pc.saturating_sub(LIST_CONTEXT);
+            for address in start..(pc + LIST_CONTEXT + 1).min(self.machine.rom().len()) {
+                let marker = if address == pc { "=>" } else { "  " };
+                let text = self.instruction(address as u16).unwrap_or_default();
+                writeln!(out, "{marker} {address:>5}  {text}")?;
+            }
+            return Ok(());
+        }
+
+        let current = self.source_line(pc);
+        let Some(centre) = line.or(current) else {
+            return Err(err("No source line for the current instruction"));
+        };
+        let start = centre.saturating_sub(LIST_CONTEXT).max(1);
+        let end = (centre + LIST_CONTEXT).min(self.source.len());
+        for number in start..=end {
+            let marker = if Some(number) == current { "=>" } else { "  " };
+            writeln!(out, "{marker} {number:>5}  {}", self.source[number - 1])?;
+        }
+        Ok(())
+    }
+
+    /// Resolves `A`, `D`, `M`, `PC`, `RAM[n]`, a variable or an address to a value
+    fn evaluate(&self, expr: &str) -> Res<(String, HackWord)> {
+        let a = self.machine.register_a();
+        Ok(match expr {
+            "A" => ("A".into(), a),
+            "D" => ("D".into(), self.machine.register_d()),
+            "M" => (
+                self.describe_ram(a.as_u16() % MEMORY_SIZE as u16),
+                self.machine.memory[a.to_usize() % MEMORY_SIZE],
+            ),
+            "PC" => ("PC".into(), HackWord::from_u16(self.machine.pc())),
+            _ => {
+                if let Some(label) = self.debug.as_ref().and_then(|d| d.label_address(expr)) {
+                    return Ok((format!("{expr} (ROM)"), HackWord::from_u16(label)));
+                }
+                let address = self.address(expr)?;
+                (
+                    self.describe_ram(address),
+                    self.machine.memory[address as usize],
+                )
+            }
+        })
+    }
+
+    /// Resolves a RAM address written as a number, `RAM[n]` or a variable name
+    fn address(&self, text: &str) -> Res<u16> {
+        let text = text.trim();
+        let text = text
+            .strip_prefix("RAM[")
+            .and_then(|t| t.strip_suffix(']'))
+            .unwrap_or(text);
+        if let Ok(number) = HackWord::parse_number(text) {
+            return Ok(number.as_u16() % MEMORY_SIZE as u16);
+        }
+        self.debug
+            .as_ref()
+            .filter(|debug| !debug.labels.contains_key(text))
+            .and_then(|debug| debug.symbols.get(text).copied())
+            .ok_or_else(|| format!("Unknown variable '{text}'").into())
+    }
+
+    /// Resolves a ROM address written as `*n`, a source line or a label
+    fn location(&self, text: &str) -> Res<u16> {
+        if let Some(address) = text.strip_prefix('*') {
+            return Ok(HackWord::parse_number(address)?.as_u16());
+        }
+        match (&self.debug, text.parse::<usize>()) {
+            (Some(debug), Ok(line)) => debug
+                .line_address(line)
+                .ok_or_else(|| format!("No instructions on or after line {line}").into()),
+            (None, Ok(address)) => Ok(address as u16),
+            (Some(debug), Err(_)) => debug
+                .label_address(text)
+                .ok_or_else(|| format!("Unknown label '{text}'").into()),
+            (None, Err(_)) => Err(err("Labels need debug info: debug a .asm file")),
+        }
+    }
+
+    /// The source text of an instruction, or its disassembly if there is no debug info
+    fn instruction(&self, pc: u16) -> Option<String> {
+        let word = self.machine.rom().get(pc as usize)?;
+        let source = self
+            .debug
+            .as_ref()
+            .and_then(|d| d.line_mappings.get(&(pc as usize)));
+        Some(match source {
+            Some((_, text)) => text.trim().to_string(),
+            None => Instruction::from(*word).to_string(),
+        })
+    }
+
+    fn source_line(&self, pc: u16) -> Option<usize> {
+        let debug = self.debug.as_ref()?;
+        debug
+            .line_mappings
+            .get(&(pc as usize))
+            .map(|&(line, _)| line)
+    }
+
+    fn describe_rom(&self, pc: u16) -> String {
+        match self.source_line(pc) {
+            Some(line) => format!("pc {pc} (line {line})"),
+            None => format!("pc {pc}"),
+        }
+    }
+
+    fn describe_ram(&self, address: u16) -> String {
+        match self.ram_names.get(&address) {
+            Some(names) => format!("RAM[{address}] ({})", names.join("/")),
+            None => format!("RAM[{address}]"),
+        }
+    }
+
+    fn describe_range(&self, range: &std::ops::RangeInclusive<u16>) -> String {
+        if range.start() == range.end() {
+            self.describe_ram(*range.start())
+        } else {
+            format!("RAM[{}..{}]", range.start(), range.end())
+        }
+    }
+}
+
+fn prompt(out: &mut impl Write) -> Res {
+    write!(out, "(hack) ")?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::compile, common::read_lines};
+
+    fn debug_mult(a: i16, b: i16, commands: &str) -> (Debugger, String) {
+        let source = read_lines("resources/mult.asm").unwrap();
+        let (instructions, debug) = compile(source.clone(), true).unwrap();
+        let mut machine = Machine::from_instructions(instructions);
+        machine.memory[0] = HackWord(a);
+        machine.memory[1] = HackWord(b);
+
+        let mut debugger = Debugger::new(machine, debug, source);
+        let mut output = Vec::new();
+        debugger.run(commands.as_bytes(), &mut output).unwrap();
+        (debugger, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let (debugger, output) = debug_mult(3, 2, "break LOOP\ncontinue\n\nprint R2\nquit\n");
+
+        assert!(output.contains("Breakpoint at pc 4 (line 10)"), "{output}");
+        assert!(output.contains("pc 4 (line 10): @R1"), "{output}");
+        assert!(output.contains("RAM[2] (ARG/R2) = 3 (0x0003)"), "{output}");
+        assert_eq!(debugger.machine().pc(), 4);
+    }
+
+    #[test]
+    fn step_and_registers() {
+        let (debugger, output) = debug_mult(3, 2, "step 2\ninfo registers\nstep\n");
+
+        assert!(output.contains("pc 2 (line 5): @END"), "{output}");
+        assert!(output.contains("A  = 0 (0x0000)"), "{output}");
+        assert!(output.contains("D  = 3 (0x0003)"), "{output}");
+        assert!(output.contains("PC = 2"), "{output}");
+        assert_eq!(debugger.machine().pc(), 3);
+    }
+
+    #[test]
+    fn watch_and_examine() {
+        let (_, output) = debug_mult(3, 2, "watch R2\nc\nc\nx/3 R0\nc\n");
+
+        assert!(
+            output.contains("Watchpoint: wrote RAM[2] (ARG/R2): 0 (0x0000) -> 3 (0x0003)"),
+            "{output}"
+        );
+        assert!(
+            output.contains("Watchpoint: wrote RAM[2] (ARG/R2): 3 (0x0003) -> 6 (0x0006)"),
+            "{output}"
+        );
+        assert!(output.contains("RAM[1] (LCL/R1) = 0 (0x0000)"), "{output}");
+        assert!(output.contains("Program halted"), "{output}");
+    }
+
+    #[test]
+    fn next_steps_over_the_loop() {
+        let (debugger, output) = debug_mult(3, 2, "break 22\nc\ndelete 22\nn\n");
+
+        // `0;JMP` back to LOOP, then round the loop until reaching the line after it
+        assert!(output.contains("Breakpoint at pc 14 (line 22)"), "{output}");
+        assert!(output.contains("pc 15: end of program"), "{output}");
+        assert_eq!(debugger.machine().memory[2], HackWord(6));
+    }
+
+    #[test]
+    fn reverse_execution() {
+        let (debugger, output) =
+            debug_mult(3, 2, "watch R2\nc\nc\nc\nrc\nrs 2\ninfo r\ngoto 0\nrs\n");
+
+        // back from the end of the program to the second write to R2
+        assert!(
+            output.contains("Watchpoint: wrote RAM[2] (ARG/R2): 3 (0x0003) -> 6 (0x0006)"),
+            "{output}"
+        );
+        assert!(output.contains("pc 12 (line 19): M=D+M"), "{output}");
+        assert!(output.contains("pc 10 (line 17): D=M"), "{output}");
+        assert!(
+            output.contains("Reached the oldest recorded step"),
+            "{output}"
+        );
+        assert_eq!(debugger.machine().memory[2], HackWord(0));
+        assert_eq!(debugger.machine().cycles(), 0);
+    }
+
+    #[test]
+    fn list_marks_the_current_line() {
+        let (_, output) = debug_mult(3, 2, "s 4\nlist\n");
+
+        assert!(output.contains("=>    10  @R1"), "{output}");
+        assert!(output.contains("       8  (LOOP)"), "{output}");
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        let (_, output) = debug_mult(3, 2, "break NOWHERE\nprint nothing\nfoo\n");
+
+        assert!(output.contains("Unknown label 'NOWHERE'"), "{output}");
+        assert!(output.contains("Unknown variable 'nothing'"), "{output}");
+        assert!(output.contains("Unknown command 'foo'"), "{output}");
+    }
+}
@@ -0,0 +1,184 @@
+//! Turning a ROM back into asm that assembles to the same words.
+//!
+//! Jump targets get labels: the program's own names when its [`AsmDebug`] is available,
+//! otherwise `L` and the address. `@SCREEN` and `@KBD` replace their addresses, and `@R0`
+//! to `@R15` replace small numbers used as addresses by the next instruction.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::{
+    asm::AsmDebug,
+    common::Res,
+    devices::{KB_MEM_SLOT, SCREEN_MEM_START},
+    hackword::HackWord,
+    instruction::{Dest, Instruction, Jump},
+};
+
+/// Disassembles a ROM into lines of asm, failing on words no asm assembles to
+pub fn disassemble(rom: &[HackWord], debug: Option<&AsmDebug>) -> Res<Vec<String>> {
+    let instructions = rom
+        .iter()
+        .enumerate()
+        .map(|(address, &word)| {
+            let instruction = Instruction::from(word);
+            match HackWord::from(instruction) == word {
+                true => Ok(instruction),
+                false => Err(format!(
+                    "ROM[{address}] ({:016b}) isn't an instruction asm can write",
+                    word.as_u16()
+                )),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // the names of each labelled ROM address, the first being the one references use
+    let mut labels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    if let Some(debug) = debug {
+        for (name, &address) in &debug.labels {
+            labels
+                .entry(address as usize)
+                .or_default()
+                .push(name.clone());
+        }
+        labels.values_mut().for_each(|names| names.sort());
+    }
+    let taken: HashSet<String> = labels.values().flatten().cloned().collect();
+    for pair in instructions.windows(2) {
+        if let [Instruction::A(target), Instruction::C { jump, .. }] = pair {
+            let target = *target as usize;
+            if *jump != Jump::Null && target <= rom.len() && !labels.contains_key(&target) {
+                let mut name = format!("L{target}");
+                while taken.contains(&name) {
+                    name.push('_');
+                }
+                labels.insert(target, vec![name]);
+            }
+        }
+    }
+
+    let mut lines = Vec::new();
+    for address in 0..=instructions.len() {
+        for name in labels.get(&address).into_iter().flatten() {
+            lines.push(format!("({name})"));
+        }
+        let next = instructions.get(address + 1);
+        match instructions.get(address) {
+            Some(&Instruction::A(value)) => {
+                lines.push(format!("@{}", symbol(value, next, &labels)))
+            }
+            Some(instruction) => lines.push(instruction.to_string()),
+            None => (),
+        }
+    }
+    Ok(lines)
+}
+
+/// How to write the value of an A-instruction, given the instruction after it
+fn symbol(value: u16, next: Option<&Instruction>, labels: &BTreeMap<usize, Vec<String>>) -> String {
+    let (jumps, uses_m) = match next {
+        Some(&Instruction::C {
+            should_deref,
+            dest: Dest { m, .. },
+            jump,
+            ..
+        }) => (jump != Jump::Null, should_deref || m),
+        _ => (false, false),
+    };
+    match labels.get(&(value as usize)) {
+        Some(names) if jumps => names[0].clone(),
+        _ => match value {
+            SCREEN_MEM_START => "SCREEN".into(),
+            KB_MEM_SLOT => "KBD".into(),
+            0..=15 if uses_m => format!("R{value}"),
+            _ => value.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::{compile, compile_file, compile_lines},
+        io::read_instructions,
+    };
+    use proptest::prelude::*;
+
+    fn reassemble(lines: Vec<String>) -> Vec<HackWord> {
+        compile(lines, false).unwrap().0
+    }
+
+    #[test]
+    fn round_trips() {
+        for path in [
+            "resources/add.hack",
+            "resources/max.hack",
+            "resources/rect.hack",
+        ] {
+            let rom = read_instructions(path).unwrap();
+            assert_eq!(reassemble(disassemble(&rom, None).unwrap()), rom, "{path}");
+        }
+        for path in ["resources/fill.asm", "resources/mult.asm"] {
+            let (rom, debug) = compile_file(path, true).unwrap();
+            for debug in [None, debug.as_ref()] {
+                let lines = disassemble(&rom, debug).unwrap();
+                assert_eq!(reassemble(lines), rom, "{path}");
+            }
+        }
+    }
+
+    #[test]
+    fn symbols_and_labels() {
+        let rom =
+            compile_lines("@16384\nD=A\n@24576\nD=M\n@13\nM=D\n@13\nD=A\n@9\nD;JGT\n@9\n0;JMP")
+                .unwrap();
+        let lines = disassemble(&rom, None).unwrap();
+
+        assert_eq!(
+            lines,
+            [
+                "@SCREEN", "D=A", "@KBD", "D=M", "@R13", "M=D", "@13", "D=A", "@L9", "(L9)",
+                "D;JGT", "@L9", "0;JMP"
+            ]
+        );
+    }
+
+    #[test]
+    fn real_labels() {
+        let (rom, debug) = compile_file("resources/mult.asm", true).unwrap();
+        let lines = disassemble(&rom, debug.as_ref()).unwrap();
+
+        assert!(lines.contains(&"(LOOP)".to_string()), "{lines:?}");
+        assert!(lines.contains(&"@LOOP".to_string()));
+        // END is just past the last instruction
+        assert_eq!(lines[lines.len() - 2..], ["0;JMP", "(END)"]);
+        let synthetic = |line: &String| {
+            line.strip_prefix("(L")
+                .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+        };
+        assert!(!lines.iter().any(synthetic), "{lines:?}");
+    }
+
+    #[test]
+    fn words_without_asm() {
+        // a C-instruction without its two unused bits set
+        let error = disassemble(&[HackWord(0), HackWord(-32768)], None).unwrap_err();
+        assert!(error.to_string().starts_with("ROM[1]"), "{error}");
+    }
+
+    fn word() -> impl Strategy<Value = HackWord> {
+        prop_oneof![
+            (0..=32767i16).prop_map(HackWord),
+            // C-instructions always have their two unused bits set
+            any::<u16>().prop_map(|bits| HackWord::from_u16(bits | 0xE000)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn random_roms_round_trip(rom in prop::collection::vec(word(), 0..64)) {
+            let lines = disassemble(&rom, None).unwrap();
+            prop_assert_eq!(reassemble(lines), rom);
+        }
+    }
+}
@@ -1,6 +1,11 @@
+pub mod capture;
+pub mod devices;
+pub mod font;
 pub mod hackword;
+pub mod input;
 pub mod instruction;
 pub mod io;
+pub mod keymap;
 pub mod machine;
 
 #[cfg(test)]
@@ -14,7 +19,7 @@ mod tests {
         let instructions = read_instructions("resources/add.hack").unwrap();
         let mut machine = Machine::from_instructions(instructions);
 
-        machine.run().unwrap();
+        machine.run();
 
         assert_eq!(machine.memory[0], HackWord(5))
     }
@@ -26,7 +31,7 @@ mod tests {
         machine.memory[0] = HackWord(5);
         machine.memory[1] = HackWord(4);
 
-        machine.run().unwrap();
+        machine.run();
 
         assert_eq!(machine.memory[2], HackWord(5))
     }
@@ -0,0 +1,374 @@
+//! Rendering the screen without a window, to PBM or PNG images.
+
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    common::{err, Res},
+    devices::{SCREEN_HEIGHT, SCREEN_MEM_START, SCREEN_WIDTH, SCREEN_WORDS},
+    hackword::HackWord,
+    io::run_headless,
+    machine::{Machine, StopReason},
+};
+
+mod golden;
+
+pub use golden::*;
+
+/// How many cycles make a frame when running headless, where there's no display to pace
+/// frames by
+pub const FRAME_CYCLES: u64 = 100_000;
+
+/// Bytes per row of a packed 1-bit image
+const ROW_BYTES: usize = SCREEN_WIDTH / 8;
+
+/// A monochrome picture of the screen, packed 8 pixels to a byte with the leftmost pixel
+/// in the most significant bit, and 1 for black as in PBM
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ScreenImage {
+    bits: Vec<u8>,
+}
+
+impl ScreenImage {
+    /// Renders the screen region of `memory`, which must cover at least the screen
+    pub fn from_memory(memory: &[HackWord]) -> Self {
+        Self::from_screen(&memory[SCREEN_MEM_START as usize..][..SCREEN_WORDS])
+    }
+
+    /// The image of just the screen's part of memory
+    pub fn from_screen(screen: &[HackWord]) -> Self {
+        // in screen memory the leftmost pixel of each word is its least significant bit
+        let bits = screen
+            .iter()
+            .flat_map(|word| {
+                let reversed = word.as_u16().reverse_bits();
+                reversed.to_be_bytes()
+            })
+            .collect();
+        Self { bits }
+    }
+
+    pub fn capture(machine: &Machine) -> Self {
+        Self::from_memory(&machine.memory)
+    }
+
+    /// Whether the pixel at (`x`, `y`) is black
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.bits[y * ROW_BYTES + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+
+    /// Writes a binary (P4) PBM
+    pub fn write_pbm(&self, mut out: impl Write) -> Res {
+        write!(out, "P4\n{SCREEN_WIDTH} {SCREEN_HEIGHT}\n")?;
+        out.write_all(&self.bits)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Writes a 1-bit greyscale PNG. The image data is stored uncompressed, which keeps the
+    /// encoder tiny at the cost of files around 17KB.
+    pub fn write_png(&self, out: impl Write) -> Res {
+        // PNG greyscale uses 1 for white
+        let rows = self
+            .bits
+            .chunks(ROW_BYTES)
+            .map(|row| row.iter().map(|byte| !byte));
+        // bit depth 1, greyscale
+        write_png(out, 1, 0, None, rows)
+    }
+
+    /// Reads a binary (P4) PBM of the screen's size
+    pub fn read_pbm(mut input: impl Read) -> Res<Self> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+
+        // the magic number, width and height, separated by whitespace and comments, then
+        // a single whitespace character before the pixels
+        let mut fields = Vec::new();
+        let mut i = 0;
+        while fields.len() < 3 {
+            match bytes.get(i) {
+                None => return Err(err("Truncated PBM header")),
+                Some(b'#') => {
+                    while bytes.get(i).is_some_and(|&b| b != b'\n') {
+                        i += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => i += 1,
+                Some(_) => {
+                    let start = i;
+                    while bytes.get(i).is_some_and(|b| !b.is_ascii_whitespace()) {
+                        i += 1;
+                    }
+                    fields.push(String::from_utf8_lossy(&bytes[start..i]).into_owned());
+                }
+            }
+        }
+        let expected = [
+            "P4".to_string(),
+            SCREEN_WIDTH.to_string(),
+            SCREEN_HEIGHT.to_string(),
+        ];
+        if fields != expected {
+            return Err(format!("Expected a {SCREEN_WIDTH}x{SCREEN_HEIGHT} P4 PBM").into());
+        }
+
+        let bits = bytes.get(i + 1..).unwrap_or_default().to_vec();
+        if bits.len() != ROW_BYTES * SCREEN_HEIGHT {
+            return Err(err("Wrong amount of PBM pixel data"));
+        }
+        Ok(Self { bits })
+    }
+
+    pub fn read_pbm_file(path: impl AsRef<Path>) -> Res<Self> {
+        Self::read_pbm(BufReader::new(File::open(path)?))
+    }
+
+    /// Saves as PNG or PBM, going by the file extension
+    pub fn save(&self, path: impl AsRef<Path>) -> Res {
+        let path = path.as_ref();
+        let format = path.extension().and_then(OsStr::to_str);
+        if !matches!(format, Some("png" | "pbm")) {
+            return Err(format!("Can't tell the image format of {}", path.display()).into());
+        }
+
+        let out = BufWriter::new(File::create(path)?);
+        match format {
+            Some("png") => self.write_png(out),
+            _ => self.write_pbm(out),
+        }
+    }
+}
+
+/// Writes a PNG of the screen's size from rows of packed pixels. The image data is stored
+/// uncompressed, which keeps the encoder tiny at the cost of larger files.
+fn write_png<R: IntoIterator<Item = u8>>(
+    mut out: impl Write,
+    bit_depth: u8,
+    colour_type: u8,
+    palette: Option<&[u8]>,
+    rows: impl IntoIterator<Item = R>,
+) -> Res {
+    // each row is a filter type byte (0, none) and the row's pixels
+    let mut raw = Vec::new();
+    for row in rows {
+        raw.push(0);
+        raw.extend(row);
+    }
+
+    // a zlib stream of stored deflate blocks
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(u16::MAX as usize).peekable();
+    while let Some(block) = blocks.next() {
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend((block.len() as u16).to_le_bytes());
+        zlib.extend((!(block.len() as u16)).to_le_bytes());
+        zlib.extend(block);
+    }
+    zlib.extend(adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend((SCREEN_WIDTH as u32).to_be_bytes());
+    header.extend((SCREEN_HEIGHT as u32).to_be_bytes());
+    // deflate, no filtering variants, no interlacing
+    header.extend([bit_depth, colour_type, 0, 0, 0]);
+
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+    write_chunk(&mut out, b"IHDR", &header)?;
+    if let Some(palette) = palette {
+        write_chunk(&mut out, b"PLTE", palette)?;
+    }
+    write_chunk(&mut out, b"IDAT", &zlib)?;
+    write_chunk(&mut out, b"IEND", &[])?;
+    out.flush()?;
+    Ok(())
+}
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> Res {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(kind.iter().chain(data));
+    out.write_all(&crc.to_be_bytes())?;
+    Ok(())
+}
+
+fn crc32<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// When to save screenshots while running headless. Captures before the end of the run
+/// are saved next to `path`, with the cycle number added to the file name.
+#[derive(Clone, Debug, Default)]
+pub struct CapturePlan {
+    pub path: PathBuf,
+    /// Capture every this many cycles
+    pub every: Option<NonZeroU64>,
+    /// Capture when the cycle count reaches each of these
+    pub at: BTreeSet<u64>,
+}
+
+impl CapturePlan {
+    /// Runs until the program halts or `max_cycles` have run, saving captures along the
+    /// way and one at `path` at the end
+    pub fn run(&self, machine: &mut Machine, max_cycles: Option<u64>) -> Res<StopReason> {
+        run_headless(machine, max_cycles, Some(self), None)
+    }
+
+    /// The first cycle after `now` with a capture due
+    pub(crate) fn next_capture(&self, now: u64) -> Option<u64> {
+        let every = self
+            .every
+            .and_then(|every| (now / every + 1).checked_mul(every.get()));
+        let at = now
+            .checked_add(1)
+            .and_then(|next| self.at.range(next..).next().copied());
+        every.into_iter().chain(at).min()
+    }
+
+    pub(crate) fn is_due(&self, now: u64) -> bool {
+        self.every
+            .is_some_and(|every| now.is_multiple_of(every.get()))
+            || self.at.contains(&now)
+    }
+
+    pub(crate) fn save_numbered(&self, machine: &Machine) -> Res {
+        let cycle = machine.cycles();
+        // built by hand: `set_extension` would replace anything after a dot in the stem
+        let mut name = self.path.file_stem().unwrap_or_default().to_os_string();
+        name.push(format!("-{cycle}"));
+        if let Some(extension) = self.path.extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        ScreenImage::capture(machine).save(self.path.with_file_name(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::compile_lines, common::TempDir, machine::MEMORY_SIZE};
+
+    fn checkerboard_corner() -> ScreenImage {
+        let mut memory = [HackWord::zero(); MEMORY_SIZE];
+        // the top-left pixel, and the rightmost pixel of the first word
+        memory[SCREEN_MEM_START as usize] = HackWord::from_u16(0x8001);
+        // the bottom-right pixel
+        memory[SCREEN_MEM_START as usize + 8191] = HackWord::from_u16(0x8000);
+        ScreenImage::from_memory(&memory)
+    }
+
+    #[test]
+    fn pixels_follow_the_screen_layout() {
+        let image = checkerboard_corner();
+
+        assert!(image.pixel(0, 0));
+        assert!(image.pixel(15, 0));
+        assert!(!image.pixel(1, 0));
+        assert!(!image.pixel(16, 0));
+        assert!(image.pixel(511, 255));
+        assert!(!image.pixel(510, 255));
+    }
+
+    #[test]
+    fn pbm() {
+        let mut pbm = Vec::new();
+        checkerboard_corner().write_pbm(&mut pbm).unwrap();
+
+        let header = b"P4\n512 256\n";
+        assert_eq!(&pbm[..header.len()], header);
+        assert_eq!(pbm.len(), header.len() + 512 * 256 / 8);
+        assert_eq!(pbm[header.len()..][..2], [0x80, 0x01]);
+        assert_eq!(pbm.last(), Some(&0x01));
+    }
+
+    #[test]
+    fn png_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+
+        let mut png = Vec::new();
+        checkerboard_corner().write_png(&mut png).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+    }
+
+    #[test]
+    fn capture_plans() {
+        let dir = TempDir::new("capture");
+        let plan = CapturePlan {
+            path: dir.path().join("screen.pbm"),
+            every: NonZeroU64::new(4),
+            at: [0, 3].into(),
+        };
+
+        let program = compile_lines("@SCREEN\nM=1\n@SCREEN\nM=-1\n@5\nD=A").unwrap();
+        let mut machine = Machine::from_instructions(program);
+        assert_eq!(plan.run(&mut machine, None).unwrap(), StopReason::Halted);
+
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            ["screen-0.pbm", "screen-3.pbm", "screen-4.pbm", "screen.pbm"]
+        );
+        let pbm = std::fs::read(dir.path().join("screen-3.pbm")).unwrap();
+        assert_eq!(pbm[11], 0x80);
+    }
+
+    #[test]
+    fn huge_capture_intervals() {
+        let plan = CapturePlan {
+            path: PathBuf::new(),
+            every: NonZeroU64::new(u64::MAX - 1),
+            at: BTreeSet::new(),
+        };
+        assert_eq!(plan.next_capture(5), Some(u64::MAX - 1));
+        assert_eq!(plan.next_capture(u64::MAX - 1), None);
+
+        let plan = CapturePlan {
+            path: PathBuf::new(),
+            every: None,
+            at: [u64::MAX].into(),
+        };
+        assert_eq!(plan.next_capture(5), Some(u64::MAX));
+        assert_eq!(plan.next_capture(u64::MAX), None);
+    }
+
+    #[test]
+    fn numbered_names_keep_dots_in_the_stem() {
+        let dir = TempDir::new("capture-dots");
+        let plan = CapturePlan {
+            path: dir.path().join("out.v1.pbm"),
+            every: None,
+            at: BTreeSet::new(),
+        };
+        plan.save_numbered(&Machine::new()).unwrap();
+
+        assert!(dir.path().join("out.v1-0.pbm").exists());
+    }
+}
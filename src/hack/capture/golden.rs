@@ -0,0 +1,153 @@
+//! Checking the screen against stored reference images, for tests.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::{write_png, ScreenImage, ROW_BYTES};
+use crate::{
+    common::Res,
+    machine::{Machine, StopReason},
+};
+
+/// Set this environment variable to overwrite golden images with the current screen rather
+/// than comparing against them
+pub const UPDATE_GOLDENS: &str = "HACK_UPDATE_GOLDENS";
+
+/// Diff image colours: white, black, then red for pixels only black on the screen and blue
+/// for pixels only black in the golden
+const DIFF_PALETTE: [u8; 12] = [255, 255, 255, 0, 0, 0, 255, 0, 0, 0, 0, 255];
+
+impl ScreenImage {
+    /// How many pixels differ from `other`
+    pub fn differences(&self, other: &Self) -> usize {
+        self.bits
+            .iter()
+            .zip(&other.bits)
+            .map(|(a, b)| (a ^ b).count_ones() as usize)
+            .sum()
+    }
+
+    /// Writes a PNG of this image, with pixels only black here in red and pixels only black
+    /// in `expected` in blue
+    pub fn write_diff(&self, expected: &Self, out: impl Write) -> Res {
+        let rows = self
+            .bits
+            .chunks(ROW_BYTES)
+            .zip(expected.bits.chunks(ROW_BYTES))
+            .map(|(actual, expected)| {
+                // two bits per pixel, so each byte of pixels becomes two
+                actual.iter().zip(expected).flat_map(|(&a, &e)| {
+                    let mut indices = 0u16;
+                    for bit in (0..8).rev() {
+                        let index = match (a >> bit & 1, e >> bit & 1) {
+                            (0, 0) => 0,
+                            (1, 1) => 1,
+                            (1, 0) => 2,
+                            _ => 3,
+                        };
+                        indices = indices << 2 | index;
+                    }
+                    indices.to_be_bytes()
+                })
+            });
+        // bit depth 2, indexed colour
+        write_png(out, 2, 3, Some(&DIFF_PALETTE), rows)
+    }
+}
+
+/// Compares the screen with the PBM at `golden`, or overwrites it if [`UPDATE_GOLDENS`] is
+/// set. On a mismatch a diff image is written beside the golden, as `<name>.diff.png`.
+pub fn check_golden(machine: &Machine, golden: impl AsRef<Path>) -> Res {
+    let golden = golden.as_ref();
+    let actual = ScreenImage::capture(machine);
+    if std::env::var_os(UPDATE_GOLDENS).is_some() {
+        return actual.save(golden);
+    }
+
+    let expected = ScreenImage::read_pbm_file(golden).map_err(|e| {
+        format!(
+            "Can't read golden image {}: {e} (set {UPDATE_GOLDENS}=1 to create it)",
+            golden.display()
+        )
+    })?;
+    let diff = golden.with_extension("diff.png");
+    let differences = actual.differences(&expected);
+    if differences == 0 {
+        // a diff left over from an earlier failure would only mislead
+        let _ = fs::remove_file(diff);
+        return Ok(());
+    }
+
+    actual.write_diff(&expected, BufWriter::new(File::create(&diff)?))?;
+    Err(format!(
+        "{differences} pixels differ from {}, see {}",
+        golden.display(),
+        diff.display()
+    )
+    .into())
+}
+
+/// Runs the machine until it halts or `max_cycles` have run, then panics unless the screen
+/// matches `golden` as [`check_golden`] does
+pub fn assert_golden(machine: &mut Machine, max_cycles: u64, golden: impl AsRef<Path>) {
+    if machine.run_with_limit(max_cycles) == StopReason::Halted {
+        assert!(
+            machine.is_halted(),
+            "Left the program at pc {}",
+            machine.pc()
+        );
+    }
+    if let Err(e) = check_golden(machine, golden) {
+        panic!("{e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::compile_file, devices::Keyboard, hackword::HackWord, io::read_instructions};
+
+    #[test]
+    fn rect() {
+        let mut machine =
+            Machine::from_instructions(read_instructions("resources/rect.hack").unwrap());
+        machine.memory[0] = HackWord(50);
+
+        assert_golden(&mut machine, 10_000, "resources/golden/rect.pbm");
+    }
+
+    #[test]
+    fn fill_part_way() {
+        let (instructions, _) = compile_file("resources/fill.asm", false).unwrap();
+        let mut machine = Machine::from_instructions(instructions);
+        machine.attach(Keyboard::default()).unwrap();
+        machine
+            .device_mut::<Keyboard>()
+            .unwrap()
+            .set_key(HackWord(65));
+
+        // past the first, blank, frame and part of the way through filling the next
+        assert_golden(&mut machine, 150_000, "resources/golden/fill.pbm");
+    }
+
+    #[test]
+    fn diffs_colour_the_differences() {
+        let mut memory = [HackWord::zero(); crate::machine::MEMORY_SIZE];
+        let blank = ScreenImage::from_memory(&memory);
+        memory[crate::devices::SCREEN_MEM_START as usize] = HackWord(0b11);
+        let two = ScreenImage::from_memory(&memory);
+        assert_eq!(two.differences(&blank), 2);
+
+        let (mut extra, mut missing) = (Vec::new(), Vec::new());
+        two.write_diff(&blank, &mut extra).unwrap();
+        blank.write_diff(&two, &mut missing).unwrap();
+        // the first row's data follows its filter byte, after the zlib and block headers
+        let idat = |png: &[u8]| {
+            let start = png.windows(4).position(|w| w == b"IDAT").unwrap() + 4;
+            png[start + 2 + 5 + 1..][..2].to_vec()
+        };
+        assert_eq!(idat(&extra), [0b1010_0000, 0]);
+        assert_eq!(idat(&missing), [0b1111_0000, 0]);
+    }
+}
@@ -0,0 +1,397 @@
+//! The Hack computer's built-in memory-mapped devices.
+
+use std::io::Write;
+use std::ops::RangeInclusive;
+
+use crate::{common::Res, hackword::HackWord, machine::Device};
+
+pub const SCREEN_MEM_START: u16 = 0x4000;
+pub const KB_MEM_SLOT: u16 = 0x6000;
+pub const SCREEN_WIDTH: usize = 512;
+pub const SCREEN_HEIGHT: usize = 256;
+/// Words of screen memory: each row is 32 words of 16 pixels
+pub const SCREEN_WORDS: usize = SCREEN_WIDTH * SCREEN_HEIGHT / 16;
+/// Where the console goes unless configured otherwise: the word after the keyboard
+pub const CONSOLE_ADDRESS: u16 = KB_MEM_SLOT + 1;
+/// The Hack character set's codes for enter and backspace
+const NEWLINE: u16 = 128;
+const BACKSPACE: u16 = 129;
+/// How much console output is kept; older output is dropped a line at a time
+const CONSOLE_LIMIT: usize = 1 << 16;
+
+/// A set of screen words, by their offset from `SCREEN_MEM_START`
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct DirtyWords {
+    bits: [u64; SCREEN_WORDS / 64],
+}
+
+impl Default for DirtyWords {
+    fn default() -> Self {
+        Self {
+            bits: [0; SCREEN_WORDS / 64],
+        }
+    }
+}
+
+impl DirtyWords {
+    /// Every word, for when the whole screen needs drawing
+    pub fn all() -> Self {
+        Self {
+            bits: [u64::MAX; SCREEN_WORDS / 64],
+        }
+    }
+
+    pub fn insert(&mut self, offset: usize) {
+        self.bits[offset / 64] |= 1 << (offset % 64);
+    }
+
+    pub fn contains(&self, offset: usize) -> bool {
+        self.bits[offset / 64] & (1 << (offset % 64)) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&bits| bits == 0)
+    }
+
+    /// Adds the words of `other`
+    pub fn merge(&mut self, other: &Self) {
+        for (bits, other) in self.bits.iter_mut().zip(other.bits) {
+            *bits |= other;
+        }
+    }
+
+    /// The offsets in the set, in order
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bits.iter().enumerate().flat_map(|(i, &bits)| {
+            (0..64)
+                .filter(move |bit| bits & (1 << bit) != 0)
+                .map(move |bit| i * 64 + bit)
+        })
+    }
+}
+
+/// The 512x256 monochrome display. Its pixels are stored in RAM from `SCREEN_MEM_START`,
+/// and the device notes which words change so frontends only redraw what they need to.
+#[derive(Clone, Debug, Default)]
+pub struct Screen {
+    dirty: DirtyWords,
+}
+
+impl Screen {
+    /// The words written since the last call
+    pub fn take_dirty(&mut self) -> DirtyWords {
+        std::mem::take(&mut self.dirty)
+    }
+}
+
+impl Device for Screen {
+    fn range(&self) -> RangeInclusive<u16> {
+        SCREEN_MEM_START..=SCREEN_MEM_START + SCREEN_WORDS as u16 - 1
+    }
+
+    fn write(&mut self, address: u16, value: HackWord) -> HackWord {
+        self.dirty.insert((address - SCREEN_MEM_START) as usize);
+        value
+    }
+
+    fn restore(&mut self, address: u16, _: HackWord) {
+        self.dirty.insert((address - SCREEN_MEM_START) as usize);
+    }
+
+    /// The pixels are all in RAM, so there's nothing to load but a redraw of everything
+    fn load_state(&mut self, _: &[u8]) -> Res {
+        self.dirty = DirtyWords::all();
+        Ok(())
+    }
+}
+
+/// The keyboard register at `KB_MEM_SLOT`, holding the code of the key being pressed or 0
+#[derive(Clone, Debug, Default)]
+pub struct Keyboard {
+    key: HackWord,
+}
+
+impl Keyboard {
+    pub fn key(&self) -> HackWord {
+        self.key
+    }
+
+    pub fn set_key(&mut self, key: HackWord) {
+        self.key = key;
+    }
+}
+
+impl Device for Keyboard {
+    fn range(&self) -> RangeInclusive<u16> {
+        KB_MEM_SLOT..=KB_MEM_SLOT
+    }
+
+    /// The keyboard is read-only to programs: reads always see the key being pressed
+    fn read(&mut self, _: u16, _: HackWord) -> HackWord {
+        self.key
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.key.as_u16().to_le_bytes().to_vec()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Res {
+        self.key = match state {
+            [] => HackWord::zero(),
+            &[low, high] => HackWord::from_u16(u16::from_le_bytes([low, high])),
+            _ => return Err("Keyboard state should be a single word".into()),
+        };
+        Ok(())
+    }
+}
+
+/// A character output port. Each word written to it is a character code, which is added
+/// to a text buffer: printable ASCII, tabs and newlines (as 10 or the Hack 128) are kept,
+/// 129 deletes the last character and anything else is ignored.
+pub struct Console {
+    address: u16,
+    text: String,
+    dirty: bool,
+    echo: Option<Box<dyn Write + Send>>,
+}
+
+impl Console {
+    pub fn new(address: u16) -> Self {
+        Self {
+            address,
+            text: String::new(),
+            dirty: false,
+            echo: None,
+        }
+    }
+
+    /// Also writes output to `echo` as it arrives, flushing after each line
+    pub fn with_echo(mut self, echo: impl Write + Send + 'static) -> Self {
+        self.echo = Some(Box::new(echo));
+        self
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Whether there has been any output since the last call
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
+    }
+
+    /// Writes to the echo, flushing at the end of a line, and stops echoing if that fails
+    fn echo(&mut self, text: &str) {
+        if let Some(echo) = &mut self.echo {
+            let mut result = echo.write_all(text.as_bytes());
+            if text.ends_with('\n') {
+                result = result.and_then(|()| echo.flush());
+            }
+            if result.is_err() {
+                self.echo = None;
+            }
+        }
+    }
+
+    fn push(&mut self, c: char) {
+        self.text.push(c);
+        if self.text.len() > CONSOLE_LIMIT {
+            let excess = self.text.len() - CONSOLE_LIMIT;
+            let cut = self.text[excess..]
+                .find('\n')
+                .map_or(excess, |i| excess + i + 1);
+            self.text.drain(..cut);
+        }
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new(CONSOLE_ADDRESS)
+    }
+}
+
+impl Device for Console {
+    fn range(&self) -> RangeInclusive<u16> {
+        self.address..=self.address
+    }
+
+    fn write(&mut self, _: u16, value: HackWord) -> HackWord {
+        let c = match value.as_u16() {
+            BACKSPACE => {
+                if self.text.pop().is_some() {
+                    self.dirty = true;
+                    // step back over the character, blank it and step back again
+                    self.echo("\u{8} \u{8}");
+                }
+                return value;
+            }
+            NEWLINE | 10 => '\n',
+            code @ (9 | 32..=126) => code as u8 as char,
+            _ => return value,
+        };
+        self.push(c);
+        self.dirty = true;
+        self.echo(c.encode_utf8(&mut [0; 4]));
+        value
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.text.as_bytes().to_vec()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Res {
+        self.text = String::from_utf8(state.to_vec())
+            .map_err(|_| "Console state should be UTF-8 text".to_string())?;
+        self.dirty = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{asm::compile_lines, machine::Machine};
+
+    #[test]
+    fn keyboard_reads_the_pressed_key() {
+        let mut machine =
+            Machine::from_instructions(compile_lines("@KBD\nM=-1\nD=M\n@0\nM=D").unwrap());
+        machine.attach(Keyboard::default()).unwrap();
+        machine
+            .device_mut::<Keyboard>()
+            .unwrap()
+            .set_key(HackWord(b'K'.into()));
+        machine.run();
+
+        assert_eq!(machine.memory[0], HackWord(b'K'.into()));
+    }
+
+    #[test]
+    fn console_collects_text() {
+        let asm = "
+            @72
+            D=A
+            @24577
+            M=D
+            @105
+            D=A
+            @24577
+            M=D
+            @128
+            D=A
+            @24577
+            M=D
+            @7
+            D=A
+            @24577
+            M=D
+        ";
+        let mut machine = Machine::from_instructions(compile_lines(asm).unwrap());
+        machine.attach(Console::default()).unwrap();
+        machine.run();
+
+        let console = machine.device_mut::<Console>().unwrap();
+        assert_eq!(console.text(), "Hi\n");
+        assert!(console.take_dirty());
+    }
+
+    #[test]
+    fn backspace_erases_the_echoed_character() {
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let echo = Shared::default();
+        let mut machine = Machine::new();
+        machine
+            .attach(Console::default().with_echo(echo.clone()))
+            .unwrap();
+        for code in [b'H'.into(), b'i'.into(), BACKSPACE, BACKSPACE, BACKSPACE] {
+            machine.poke(CONSOLE_ADDRESS, HackWord::from_u16(code));
+        }
+
+        assert_eq!(machine.device::<Console>().unwrap().text(), "");
+        let echoed = echo.0.lock().unwrap().clone();
+        assert_eq!(
+            String::from_utf8(echoed).unwrap(),
+            "Hi\u{8} \u{8}\u{8} \u{8}"
+        );
+    }
+
+    #[test]
+    fn print_int_routine() {
+        let mut program = String::new();
+        for (i, value) in [0i16, 7, -45, 1203, 32767, -32767].into_iter().enumerate() {
+            let negate = if value < 0 { "D=-D" } else { "" };
+            program += &format!(
+                "@{}\nD=A\n{negate}\n@R13\nM=D\n@RETURN{i}\nD=A\n@R14\nM=D\n@PRINT_INT\n0;JMP\n\
+                 (RETURN{i})\n@32\nD=A\n@CONSOLE\nM=D\n",
+                value.abs()
+            );
+        }
+        program += "(END)\n@END\n0;JMP\n";
+        program += &std::fs::read_to_string("resources/console.asm").unwrap();
+
+        let mut machine = Machine::from_instructions(compile_lines(&program).unwrap());
+        machine.attach(Console::default()).unwrap();
+        machine.run();
+
+        assert!(machine.is_halted());
+        assert_eq!(
+            machine.device::<Console>().unwrap().text(),
+            "0 7 -45 1203 32767 -32767 "
+        );
+    }
+
+    #[test]
+    fn screen_notes_writes() {
+        let mut machine = Machine::from_instructions(
+            compile_lines("@0\nM=1\n@SCREEN\nD=M\n@24575\nM=-1").unwrap(),
+        );
+        machine.attach(Screen::default()).unwrap();
+        machine.attach(Keyboard::default()).unwrap();
+        for _ in 0..4 {
+            machine.step();
+        }
+        assert!(machine
+            .device_mut::<Screen>()
+            .unwrap()
+            .take_dirty()
+            .is_empty());
+
+        machine.run();
+        let screen = machine.device_mut::<Screen>().unwrap();
+        let dirty = screen.take_dirty();
+        assert_eq!(dirty.iter().collect::<Vec<_>>(), [SCREEN_WORDS - 1]);
+        assert!(screen.take_dirty().is_empty());
+        assert_eq!(machine.memory[24575], HackWord::minus_one());
+    }
+
+    #[test]
+    fn dirty_word_sets() {
+        let mut dirty = DirtyWords::default();
+        dirty.insert(3);
+        dirty.insert(64);
+        let mut other = DirtyWords::default();
+        other.insert(SCREEN_WORDS - 1);
+        dirty.merge(&other);
+
+        assert!(dirty.contains(64) && !dirty.contains(65));
+        assert_eq!(dirty.iter().collect::<Vec<_>>(), [3, 64, SCREEN_WORDS - 1]);
+        assert_eq!(DirtyWords::all().iter().count(), SCREEN_WORDS);
+        assert!(DirtyWords::default().is_empty());
+    }
+}
@@ -5,16 +5,31 @@ use std::{error::Error, ops::Not};
 
 use crate::common::err;
 
+/// A 16-bit Hack word. Arithmetic wraps in two's complement, as the Hack ALU does.
 #[derive(Clone, Copy, Eq, PartialEq, Hash, Default)]
 pub struct HackWord(pub i16);
 
 impl HackWord {
-    pub fn bit(&self, b: u8) -> bool {
+    pub fn from_u16(value: u16) -> HackWord {
+        HackWord(value as i16)
+    }
+
+    /// The word read as an unsigned number
+    pub fn as_u16(self) -> u16 {
+        self.0 as u16
+    }
+
+    /// The word read as a two's-complement signed number
+    pub fn as_i16(self) -> i16 {
+        self.0
+    }
+
+    pub fn bit(self, b: u8) -> bool {
         let offset = 15 - b;
         (self.0 & (1 << offset)) != 0
     }
 
-    pub fn to_usize(&self) -> usize {
+    pub fn to_usize(self) -> usize {
         (self.0 as u16) as usize
     }
 
@@ -32,6 +47,46 @@ impl HackWord {
     pub fn minus_one() -> HackWord {
         HackWord(-1)
     }
+
+    /// The signed value followed by its hex, as the debugger and inspector show registers:
+    /// `-2 (0xfffe)`
+    pub fn describe(self) -> String {
+        format!("{self} ({self:#06x})")
+    }
+
+    /// Parses a number written in decimal (`-5`, `65535`), hex (`0x4000`) or binary
+    /// (`0b101`), with an optional leading `-`. Values up to 65535 are accepted and read
+    /// as the unsigned view of the word.
+    pub fn parse_number(s: &str) -> Result<HackWord, Box<dyn Error>> {
+        let s = s.trim();
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let (radix, digits) = if let Some(hex) = digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+        {
+            (16, hex)
+        } else if let Some(bin) = digits
+            .strip_prefix("0b")
+            .or_else(|| digits.strip_prefix("0B"))
+        {
+            (2, bin)
+        } else {
+            (10, digits)
+        };
+
+        let magnitude = u32::from_str_radix(digits, radix)
+            .map_err(|_| err(&format!("'{s}' is not a number")))?;
+        let limit = if negative { 1 << 15 } else { u16::MAX as u32 };
+        if magnitude > limit {
+            return Err(format!("'{s}' does not fit in 16 bits").into());
+        }
+
+        let word = HackWord::from_u16(magnitude as u16);
+        Ok(if negative { -word } else { word })
+    }
 }
 
 impl fmt::Debug for HackWord {
@@ -40,6 +95,30 @@ impl fmt::Debug for HackWord {
     }
 }
 
+impl fmt::Display for HackWord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl fmt::LowerHex for HackWord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.as_u16(), f)
+    }
+}
+
+impl fmt::UpperHex for HackWord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::UpperHex::fmt(&self.as_u16(), f)
+    }
+}
+
+impl fmt::Binary for HackWord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Binary::fmt(&self.as_u16(), f)
+    }
+}
+
 impl Not for HackWord {
     type Output = HackWord;
 
@@ -52,7 +131,7 @@ impl Add for HackWord {
     type Output = HackWord;
 
     fn add(self, rhs: Self) -> Self::Output {
-        HackWord(self.0 + rhs.0)
+        HackWord(self.0.wrapping_add(rhs.0))
     }
 }
 
@@ -60,7 +139,7 @@ impl Sub for HackWord {
     type Output = HackWord;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        HackWord(self.0 - rhs.0)
+        HackWord(self.0.wrapping_sub(rhs.0))
     }
 }
 
@@ -68,7 +147,7 @@ impl Neg for HackWord {
     type Output = HackWord;
 
     fn neg(self) -> Self::Output {
-        HackWord(-self.0)
+        HackWord(self.0.wrapping_neg())
     }
 }
 
@@ -102,6 +181,7 @@ impl FromStr for HackWord {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn bitstring_to_hackword_and_back() {
@@ -146,4 +226,97 @@ mod tests {
             assert!(word.bit(bit))
         }
     }
+
+    #[test]
+    fn overflow_wraps() {
+        assert_eq!(HackWord(i16::MAX) + HackWord::one(), HackWord(i16::MIN));
+        assert_eq!(HackWord(i16::MIN) - HackWord::one(), HackWord(i16::MAX));
+        assert_eq!(-HackWord(i16::MIN), HackWord(i16::MIN));
+    }
+
+    #[test]
+    fn parse_numbers() {
+        for (input, expected) in [
+            ("0", 0),
+            ("32767", 32767),
+            ("32768", i16::MIN),
+            ("65535", -1),
+            ("-1", -1),
+            ("-32768", i16::MIN),
+            ("0x4000", 0x4000),
+            ("0XFFFF", -1),
+            ("0b101", 5),
+            ("-0x1", -1),
+            (" 24576 ", 24576),
+        ] {
+            assert_eq!(
+                HackWord::parse_number(input).unwrap(),
+                HackWord(expected),
+                "{input}"
+            );
+        }
+
+        for input in ["", "-", "65536", "-32769", "0x10000", "0b2", "12a", "--1"] {
+            assert!(HackWord::parse_number(input).is_err(), "{input}");
+        }
+    }
+
+    #[test]
+    fn display_formats() {
+        let word = HackWord(-2);
+
+        assert_eq!(format!("{word}"), "-2");
+        assert_eq!(format!("{word:x}"), "fffe");
+        assert_eq!(format!("{word:#06X}"), "0xFFFE");
+        assert_eq!(format!("{:b}", HackWord(5)), "101");
+        assert_eq!(word.describe(), "-2 (0xfffe)");
+    }
+
+    proptest! {
+        #[test]
+        fn add_matches_u16(a: u16, b: u16) {
+            let res = HackWord::from_u16(a) + HackWord::from_u16(b);
+            prop_assert_eq!(res.as_u16(), a.wrapping_add(b));
+        }
+
+        #[test]
+        fn sub_matches_u16(a: u16, b: u16) {
+            let res = HackWord::from_u16(a) - HackWord::from_u16(b);
+            prop_assert_eq!(res.as_u16(), a.wrapping_sub(b));
+        }
+
+        #[test]
+        fn neg_matches_u16(a: u16) {
+            prop_assert_eq!((-HackWord::from_u16(a)).as_u16(), a.wrapping_neg());
+        }
+
+        #[test]
+        fn bitwise_matches_u16(a: u16, b: u16) {
+            let (x, y) = (HackWord::from_u16(a), HackWord::from_u16(b));
+            prop_assert_eq!((!x).as_u16(), !a);
+            prop_assert_eq!((x & y).as_u16(), a & b);
+            prop_assert_eq!((x | y).as_u16(), a | b);
+        }
+
+        #[test]
+        fn signed_and_unsigned_views_agree(a: u16) {
+            let word = HackWord::from_u16(a);
+            prop_assert_eq!(word.as_u16(), a);
+            prop_assert_eq!(word.as_i16() as u16, a);
+            prop_assert_eq!(word.to_usize(), a as usize);
+        }
+
+        #[test]
+        fn display_parses_back(a: u16) {
+            let word = HackWord::from_u16(a);
+            for text in [
+                format!("{word}"),
+                format!("{}", word.as_u16()),
+                format!("{word:#x}"),
+                format!("{word:#b}"),
+            ] {
+                prop_assert_eq!(HackWord::parse_number(&text).unwrap(), word);
+            }
+        }
+    }
 }
@@ -0,0 +1,430 @@
+//! Scripted keyboard input, and recording live input as a script.
+//!
+//! A script has one event per line, a time then an action, with `#` starting a comment.
+//! Times are cycle counts, or frame numbers when they end in `f`. `key` sets the keyboard
+//! register, holding it until the next event, and `type` presses and releases a key per
+//! character, each for `hold` and `gap` cycles (or frames):
+//!
+//! ```text
+//! 150000 key 65          # hold A
+//! 200000 key 0
+//! 20f key 'a'
+//! 30f key enter
+//! 400000 type "HI\n" hold=20000 gap=20000
+//! ```
+//!
+//! Keys are numbers, quoted characters or names such as `enter`, `left` and `f1`, and
+//! strings may use `\n` for enter, `\b` for backspace, `\"` and `\\`.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::Path;
+
+use crate::{
+    common::{err, Res},
+    devices::Keyboard,
+    hackword::HackWord,
+    machine::Machine,
+};
+
+/// How long `type` holds each key down, and then leaves the keyboard released, unless the
+/// script says otherwise
+const DEFAULT_HOLD_CYCLES: u64 = 20_000;
+const DEFAULT_GAP_CYCLES: u64 = 20_000;
+const DEFAULT_HOLD_FRAMES: u64 = 2;
+const DEFAULT_GAP_FRAMES: u64 = 2;
+
+/// The Hack character set's codes for keys without a character
+const NAMED_KEYS: [(&str, i16); 25] = [
+    ("enter", 128),
+    ("backspace", 129),
+    ("left", 130),
+    ("up", 131),
+    ("right", 132),
+    ("down", 133),
+    ("home", 134),
+    ("end", 135),
+    ("pageup", 136),
+    ("pagedown", 137),
+    ("insert", 138),
+    ("delete", 139),
+    ("esc", 140),
+    ("f1", 141),
+    ("f2", 142),
+    ("f3", 143),
+    ("f4", 144),
+    ("f5", 145),
+    ("f6", 146),
+    ("f7", 147),
+    ("f8", 148),
+    ("f9", 149),
+    ("f10", 150),
+    ("f11", 151),
+    ("f12", 152),
+];
+
+/// When an input event happens
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub enum When {
+    Cycle(u64),
+    Frame(u64),
+}
+
+/// Setting the keyboard register to `key` at a given time
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct InputEvent {
+    pub when: When,
+    pub key: HackWord,
+}
+
+/// A parsed input script, with `type` actions expanded into presses and releases
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct InputScript {
+    pub events: Vec<InputEvent>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Res<Self> {
+        let mut events = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            parse_line(line, &mut events).map_err(|e| format!("Line {}: {e}", i + 1))?;
+        }
+        Ok(Self { events })
+    }
+
+    pub fn read_file(path: impl AsRef<Path>) -> Res<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+}
+
+fn parse_line(line: &str, events: &mut Vec<InputEvent>) -> Res {
+    let tokens = tokenize(line)?;
+    let Some((time, rest)) = tokens.split_first() else {
+        return Ok(());
+    };
+    let when = match time.strip_suffix('f') {
+        Some(frame) => When::Frame(frame.parse()?),
+        None => When::Cycle(time.parse()?),
+    };
+
+    match rest {
+        [action, key] if action == "key" => events.push(InputEvent {
+            when,
+            key: parse_key(key)?,
+        }),
+        [action, text, options @ ..] if action == "type" => {
+            let Some(text) = text.strip_prefix('"') else {
+                return Err(err("Expected a quoted string to type"));
+            };
+            let (mut hold, mut gap) = match when {
+                When::Cycle(_) => (DEFAULT_HOLD_CYCLES, DEFAULT_GAP_CYCLES),
+                When::Frame(_) => (DEFAULT_HOLD_FRAMES, DEFAULT_GAP_FRAMES),
+            };
+            for option in options {
+                match option.split_once('=') {
+                    Some(("hold", n)) => hold = n.parse()?,
+                    Some(("gap", n)) => gap = n.parse()?,
+                    _ => return Err(format!("Unknown option '{option}'").into()),
+                }
+            }
+
+            let mut at = match when {
+                When::Cycle(n) | When::Frame(n) => n,
+            };
+            let after = |n| match when {
+                When::Cycle(_) => When::Cycle(n),
+                When::Frame(_) => When::Frame(n),
+            };
+            let too_late = || err("Typing goes on past the last time that can be counted");
+            for c in text.chars() {
+                let release = at.checked_add(hold).ok_or_else(too_late)?;
+                events.push(InputEvent {
+                    when: after(at),
+                    key: char_code(c)?,
+                });
+                events.push(InputEvent {
+                    when: after(release),
+                    key: HackWord(0),
+                });
+                at = release.checked_add(gap).ok_or_else(too_late)?;
+            }
+        }
+        _ => return Err(err("Expected 'key <key>' or 'type \"<text>\"'")),
+    }
+    Ok(())
+}
+
+/// Splits a line into whitespace-separated words, dropping comments. Quoted strings become
+/// a single word starting with the quote (`"` or `'`), with escapes resolved.
+pub(crate) fn tokenize(line: &str) -> Res<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '#' => break,
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut token = c.to_string();
+                loop {
+                    match chars.next() {
+                        None => return Err(err("Unterminated string")),
+                        Some(end) if end == c => break,
+                        Some('\\') => token.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('b') => '\u{8}',
+                            Some(escaped @ ('"' | '\'' | '\\')) => escaped,
+                            _ => return Err(err("Unknown escape in string")),
+                        }),
+                        Some(other) => token.push(other),
+                    }
+                }
+                tokens.push(token);
+            }
+            _ => {
+                let mut token = String::new();
+                while let Some(&c) = chars.peek().filter(|c| !c.is_whitespace() && **c != '#') {
+                    token.push(c);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+pub(crate) fn parse_key(key: &str) -> Res<HackWord> {
+    if let Some(quoted) = key.strip_prefix(['\'', '"']) {
+        let mut chars = quoted.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) => char_code(c),
+            _ => Err(err("Expected a single character")),
+        };
+    }
+    if let Some(&(_, code)) = NAMED_KEYS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(key))
+    {
+        return Ok(HackWord(code));
+    }
+    HackWord::parse_number(key).map_err(|_| format!("Unknown key '{key}'").into())
+}
+
+/// The key code for a character in a script, where `\n` and `\b` are enter and backspace
+fn char_code(c: char) -> Res<HackWord> {
+    match c {
+        '\n' => Ok(HackWord(128)),
+        '\u{8}' => Ok(HackWord(129)),
+        ' '..='~' => Ok(HackWord(c as i16)),
+        _ => Err(format!("No key types {c:?}").into()),
+    }
+}
+
+/// Plays a script into a machine's [`Keyboard`]
+#[derive(Clone, Debug, Default)]
+pub struct InputPlayer {
+    cycles: VecDeque<(u64, HackWord)>,
+    frames: VecDeque<(u64, HackWord)>,
+}
+
+impl InputPlayer {
+    /// Plays frame-numbered events on frames of a window's running time, a tenth of a
+    /// second each
+    pub fn new(script: &InputScript) -> Self {
+        let mut player = Self::default();
+        for event in &script.events {
+            match event.when {
+                When::Cycle(n) => player.cycles.push_back((n, event.key)),
+                When::Frame(n) => player.frames.push_back((n, event.key)),
+            }
+        }
+        player.cycles.make_contiguous().sort_by_key(|&(n, _)| n);
+        player.frames.make_contiguous().sort_by_key(|&(n, _)| n);
+        player
+    }
+
+    /// Plays frame-numbered events every `frame_cycles` cycles, for running without a window
+    pub fn headless(script: &InputScript, frame_cycles: u64) -> Res<Self> {
+        let mut events = script.events.clone();
+        for event in &mut events {
+            if let When::Frame(n) = event.when {
+                let cycle = n
+                    .checked_mul(frame_cycles)
+                    .ok_or_else(|| format!("Frame {n} is too late to count in cycles"))?;
+                event.when = When::Cycle(cycle);
+            }
+        }
+        Ok(Self::new(&InputScript { events }))
+    }
+
+    /// The cycle the next cycle-numbered event is due at
+    pub fn next_cycle(&self) -> Option<u64> {
+        self.cycles.front().map(|&(n, _)| n)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.cycles.is_empty() && self.frames.is_empty()
+    }
+
+    /// Applies every event due by the machine's cycle count or `frame`. Without a
+    /// [`Keyboard`] attached the events are dropped, so the script still finishes.
+    pub fn apply(&mut self, machine: &mut Machine, frame: u64) {
+        let cycle = machine.cycles();
+        let mut keyboard = machine.device_mut::<Keyboard>();
+        for (queue, now) in [(&mut self.cycles, cycle), (&mut self.frames, frame)] {
+            while let Some(&(_, key)) = queue.front().filter(|&&(n, _)| n <= now) {
+                if let Some(keyboard) = &mut keyboard {
+                    keyboard.set_key(key);
+                }
+                queue.pop_front();
+            }
+        }
+    }
+}
+
+/// Records changes to the keyboard register as an input script
+#[derive(Clone, Debug, Default)]
+pub struct InputRecorder {
+    events: Vec<InputEvent>,
+    last: HackWord,
+}
+
+impl InputRecorder {
+    /// Notes the key being pressed at `cycle`, if it changed
+    pub fn record(&mut self, cycle: u64, key: HackWord) {
+        if key != self.last {
+            self.events.push(InputEvent {
+                when: When::Cycle(cycle),
+                key,
+            });
+            self.last = key;
+        }
+    }
+
+    pub fn script(&self) -> InputScript {
+        InputScript {
+            events: self.events.clone(),
+        }
+    }
+
+    pub fn write(&self, mut out: impl Write) -> Res {
+        writeln!(out, "# recorded keyboard input")?;
+        for event in &self.events {
+            let (When::Cycle(n) | When::Frame(n)) = event.when;
+            let suffix = if matches!(event.when, When::Frame(_)) {
+                "f"
+            } else {
+                ""
+            };
+            writeln!(out, "{n}{suffix} key {}", event.key)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::compile_file;
+
+    fn events(script: &str) -> Vec<(When, i16)> {
+        InputScript::parse(script)
+            .unwrap()
+            .events
+            .iter()
+            .map(|e| (e.when, e.key.0))
+            .collect()
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            events("# a comment\n\n100 key 65\n2f key 'a' # held\n3f key enter\n300 key 0x20"),
+            [
+                (When::Cycle(100), 65),
+                (When::Frame(2), 97),
+                (When::Frame(3), 128),
+                (When::Cycle(300), 32),
+            ]
+        );
+        assert_eq!(
+            events(r#"10 type "a\n" hold=5 gap=1"#),
+            [
+                (When::Cycle(10), 97),
+                (When::Cycle(15), 0),
+                (When::Cycle(16), 128),
+                (When::Cycle(21), 0),
+            ]
+        );
+
+        for bad in [
+            "10",
+            "x key 1",
+            "10 key nothing",
+            "10 type abc",
+            "10 type \"a",
+            "10 press 1",
+        ] {
+            assert!(InputScript::parse(bad).is_err(), "{bad}");
+        }
+        let error = InputScript::parse("1 key 1\n2 key 'ab'").unwrap_err();
+        assert!(error.to_string().starts_with("Line 2:"), "{error}");
+        let error =
+            InputScript::parse(&format!("1 key 1\n{} type \"ab\"", u64::MAX - 5)).unwrap_err();
+        assert!(error.to_string().starts_with("Line 2:"), "{error}");
+    }
+
+    #[test]
+    fn playing_without_a_keyboard() {
+        let script = InputScript::parse("1 key 65\n2f key 0").unwrap();
+        let mut player = InputPlayer::headless(&script, 10).unwrap();
+        let mut machine = Machine::new();
+        machine.tick();
+        player.apply(&mut machine, 0);
+        assert_eq!(player.next_cycle(), Some(20));
+
+        let script = InputScript::parse(&format!("{}f key 0", u64::MAX / 2)).unwrap();
+        assert!(InputPlayer::headless(&script, 10).is_err());
+    }
+
+    #[test]
+    fn recordings_replay() {
+        let mut recorder = InputRecorder::default();
+        for (cycle, key) in [(0, 0), (5, 65), (6, 65), (9, 0)] {
+            recorder.record(cycle, HackWord(key));
+        }
+        let mut text = Vec::new();
+        recorder.write(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+
+        assert_eq!(text, "# recorded keyboard input\n5 key 65\n9 key 0\n");
+        assert_eq!(InputScript::parse(&text).unwrap(), recorder.script());
+    }
+
+    #[test]
+    fn fill_follows_the_script() {
+        let (instructions, _) = compile_file("resources/fill.asm", false).unwrap();
+        let mut machine = Machine::from_instructions(instructions);
+        machine.attach(Keyboard::default()).unwrap();
+        let script = InputScript::parse("1f key 'x'\n500000 key 0").unwrap();
+        let mut player = InputPlayer::headless(&script, 100_000).unwrap();
+
+        let last_word = crate::devices::SCREEN_MEM_START as usize + 8191;
+        let mut blackened = None;
+        while machine.cycles() < 800_000 {
+            player.apply(&mut machine, 0);
+            machine.step();
+            if blackened.is_none() && machine.memory[last_word] == HackWord(-1) {
+                blackened = Some(machine.cycles());
+            }
+        }
+
+        assert!(player.is_done());
+        assert!(blackened.is_some_and(|cycle| (100_000..500_000).contains(&cycle)));
+        assert_eq!(machine.memory[last_word], HackWord(0));
+    }
+}
@@ -1,28 +1,222 @@
-use std::error::Error;
+use core::fmt;
 
 use crate::common::{err, Res};
 use crate::hackword::HackWord;
 
-#[derive(Clone, Copy, Eq, PartialEq, Debug)]
-pub enum Comp {
-    Zero,
-    One,
-    MinusOne,
-    D,
-    A,
-    NotD,
-    NotA,
-    MinusD,
-    MinusA,
-    DPlus1,
-    APlus1,
-    DMinus1,
-    AMinus1,
-    DPlusA,
-    DMinusA,
-    AMinusD,
-    DAndA,
-    DOrA,
+/// The six ALU control bits of a C-instruction: `zx nx zy ny f no`, most significant first.
+///
+/// Every combination is a valid computation; the ones documented in the Hack specification
+/// have named aliases.
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Comp(u8);
+
+#[allow(non_upper_case_globals)]
+impl Comp {
+    pub const Zero: Comp = Comp(0b101010);
+    pub const One: Comp = Comp(0b111111);
+    pub const MinusOne: Comp = Comp(0b111010);
+    pub const D: Comp = Comp(0b001100);
+    pub const A: Comp = Comp(0b110000);
+    pub const NotD: Comp = Comp(0b001101);
+    pub const NotA: Comp = Comp(0b110001);
+    pub const MinusD: Comp = Comp(0b001111);
+    pub const MinusA: Comp = Comp(0b110011);
+    pub const DPlus1: Comp = Comp(0b011111);
+    pub const APlus1: Comp = Comp(0b110111);
+    pub const DMinus1: Comp = Comp(0b001110);
+    pub const AMinus1: Comp = Comp(0b110010);
+    pub const DPlusA: Comp = Comp(0b000010);
+    pub const DMinusA: Comp = Comp(0b010011);
+    pub const AMinusD: Comp = Comp(0b000111);
+    pub const DAndA: Comp = Comp(0b000000);
+    pub const DOrA: Comp = Comp(0b010101);
+}
+
+/// The documented computations: alias name and mnemonic (with `A` as the second operand)
+const MNEMONICS: [(Comp, &str, &str); 18] = [
+    (Comp::Zero, "Zero", "0"),
+    (Comp::One, "One", "1"),
+    (Comp::MinusOne, "MinusOne", "-1"),
+    (Comp::D, "D", "D"),
+    (Comp::A, "A", "A"),
+    (Comp::NotD, "NotD", "!D"),
+    (Comp::NotA, "NotA", "!A"),
+    (Comp::MinusD, "MinusD", "-D"),
+    (Comp::MinusA, "MinusA", "-A"),
+    (Comp::DPlus1, "DPlus1", "D+1"),
+    (Comp::APlus1, "APlus1", "A+1"),
+    (Comp::DMinus1, "DMinus1", "D-1"),
+    (Comp::AMinus1, "AMinus1", "A-1"),
+    (Comp::DPlusA, "DPlusA", "D+A"),
+    (Comp::DMinusA, "DMinusA", "D-A"),
+    (Comp::AMinusD, "AMinusD", "A-D"),
+    (Comp::DAndA, "DAndA", "D&A"),
+    (Comp::DOrA, "DOrA", "D|A"),
+];
+
+/// Prefix for a comp written as raw `a c1 c2 c3 c4 c5 c6` bits, e.g. `D=%0000001`
+pub const RAW_COMP_PREFIX: char = '%';
+
+impl Comp {
+    /// Builds a computation from the low six bits of `bits`
+    pub const fn from_bits(bits: u8) -> Comp {
+        Comp(bits & 0b111111)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Zero the D input
+    pub fn zx(self) -> bool {
+        self.0 & 0b100000 != 0
+    }
+
+    /// Negate the D input
+    pub fn nx(self) -> bool {
+        self.0 & 0b010000 != 0
+    }
+
+    /// Zero the A/M input
+    pub fn zy(self) -> bool {
+        self.0 & 0b001000 != 0
+    }
+
+    /// Negate the A/M input
+    pub fn ny(self) -> bool {
+        self.0 & 0b000100 != 0
+    }
+
+    /// Add the inputs if set, otherwise AND them
+    pub fn f(self) -> bool {
+        self.0 & 0b000010 != 0
+    }
+
+    /// Negate the output
+    pub fn no(self) -> bool {
+        self.0 & 0b000001 != 0
+    }
+
+    /// Runs the ALU with `d` as its first input and `a` (A or M) as its second
+    #[inline]
+    pub fn compute(self, d: HackWord, a: HackWord) -> HackWord {
+        // shortcuts for the documented computations, which agree with the control bits
+        match self {
+            Comp::Zero => HackWord::zero(),
+            Comp::One => HackWord::one(),
+            Comp::MinusOne => HackWord::minus_one(),
+            Comp::D => d,
+            Comp::A => a,
+            Comp::NotD => !d,
+            Comp::NotA => !a,
+            Comp::MinusD => -d,
+            Comp::MinusA => -a,
+            Comp::DPlus1 => d + HackWord::one(),
+            Comp::APlus1 => a + HackWord::one(),
+            Comp::DMinus1 => d - HackWord::one(),
+            Comp::AMinus1 => a - HackWord::one(),
+            Comp::DPlusA => d + a,
+            Comp::DMinusA => d - a,
+            Comp::AMinusD => a - d,
+            Comp::DAndA => d & a,
+            Comp::DOrA => d | a,
+            _ => self.compute_bits(d, a),
+        }
+    }
+
+    /// Runs the ALU gate by gate from the control bits
+    pub fn compute_bits(self, d: HackWord, a: HackWord) -> HackWord {
+        let x = if self.zx() { HackWord::zero() } else { d };
+        let x = if self.nx() { !x } else { x };
+        let y = if self.zy() { HackWord::zero() } else { a };
+        let y = if self.ny() { !y } else { y };
+        let out = if self.f() { x + y } else { x & y };
+        if self.no() {
+            !out
+        } else {
+            out
+        }
+    }
+
+    /// The control bits spelled out, e.g. `zx=0 nx=1 zy=1 ny=1 f=1 no=1` for `D+1`
+    pub fn describe_bits(self) -> String {
+        let bits = [
+            self.zx(),
+            self.nx(),
+            self.zy(),
+            self.ny(),
+            self.f(),
+            self.no(),
+        ];
+        ["zx", "nx", "zy", "ny", "f", "no"]
+            .iter()
+            .zip(bits)
+            .map(|(name, bit)| format!("{name}={}", bit as u8))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The documented mnemonic for this computation, using `A` as the second operand
+    pub fn mnemonic(self) -> Option<&'static str> {
+        MNEMONICS
+            .iter()
+            .find(|(comp, _, _)| *comp == self)
+            .map(|&(_, _, mnemonic)| mnemonic)
+    }
+
+    /// The asm for this computation: the documented mnemonic if there is one, otherwise
+    /// the raw bits. Reading M without an operand to replace (e.g. `0` with the `a` bit
+    /// set) is also written raw, so the asm always assembles back to the same word.
+    pub fn to_asm(self, should_deref: bool) -> String {
+        match self.mnemonic() {
+            Some(mnemonic) if !should_deref => mnemonic.into(),
+            Some(mnemonic) if mnemonic.contains('A') => mnemonic.replace('A', "M"),
+            _ => format!("{RAW_COMP_PREFIX}{}{:06b}", should_deref as u8, self.0),
+        }
+    }
+
+    /// Parses a comp mnemonic or raw bits, returning the computation and whether it reads M
+    pub fn parse_asm(s: &str) -> Res<(Comp, bool)> {
+        if let Some(bits) = s.strip_prefix(RAW_COMP_PREFIX) {
+            if bits.len() != 7 {
+                return Err(format!("Raw comp '{s}' must have 7 bits").into());
+            }
+            let bits = u8::from_str_radix(bits, 2)
+                .map_err(|_| err(&format!("Raw comp '{s}' is not a binary number")))?;
+            return Ok((Comp::from_bits(bits), bits & 0b1000000 != 0));
+        }
+
+        let should_deref = s.contains('M');
+        if should_deref && s.contains('A') {
+            return Err(format!("Comp '{s}' cannot use both A and M").into());
+        }
+        let mnemonic = s.replace('M', "A");
+        MNEMONICS
+            .iter()
+            .find(|(_, _, m)| *m == mnemonic)
+            .map(|&(comp, _, _)| (comp, should_deref))
+            .ok_or_else(|| format!("Unrecognised comp '{s}'").into())
+    }
+}
+
+impl fmt::Debug for Comp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match MNEMONICS.iter().find(|(comp, _, _)| comp == self) {
+            Some((_, name, _)) => write!(f, "Comp::{name}"),
+            None => write!(f, "Comp({:06b})", self.0),
+        }
+    }
+}
+
+/// Prints the mnemonic, or with `{:#}` the mnemonic followed by the control bits
+impl fmt::Display for Comp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_asm(false))?;
+        if f.alternate() {
+            write!(f, " ({})", self.describe_bits())?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
@@ -32,6 +226,7 @@ pub struct Dest {
     pub m: bool,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
 pub enum Jump {
     #[default]
@@ -71,35 +266,62 @@ pub enum Instruction {
     },
 }
 
-impl TryFrom<HackWord> for Instruction {
-    type Error = Box<dyn Error>;
+impl fmt::Display for Dest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (set, name) in [(self.a, "A"), (self.m, "M"), (self.d, "D")] {
+            if set {
+                f.write_str(name)?;
+            }
+        }
+        Ok(())
+    }
+}
 
-    fn try_from(word: HackWord) -> Res<Self> {
-        Ok(if !word.bit(0) {
+impl fmt::Display for Jump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Jump::Null => Ok(()),
+            jump => write!(f, "{jump:?}"),
+        }
+    }
+}
+
+/// Prints the instruction as asm, e.g. `@123` or `AM=M+1;JGT`. With `{:#}`, C-instructions
+/// are followed by their `a` bit and ALU control bits, e.g.
+/// `AM=M+1;JGT (a=1 zx=1 nx=1 zy=0 ny=1 f=1 no=1)`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::A(x) => write!(f, "@{x}"),
+            Instruction::C {
+                comp,
+                should_deref,
+                dest,
+                jump,
+            } => {
+                if *dest != Dest::default() {
+                    write!(f, "{dest}=")?;
+                }
+                f.write_str(&comp.to_asm(*should_deref))?;
+                if *jump != Jump::Null {
+                    write!(f, ";{jump}")?;
+                }
+                if f.alternate() {
+                    write!(f, " (a={} {})", *should_deref as u8, comp.describe_bits())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<HackWord> for Instruction {
+    fn from(word: HackWord) -> Self {
+        if !word.bit(0) {
             Instruction::A(word.0 as u16)
         } else {
             let should_deref = word.bit(3);
-            let comp = match (word.0 & (0b111111 << 6)) >> 6 {
-                0b101010 => Comp::Zero,
-                0b111111 => Comp::One,
-                0b111010 => Comp::MinusOne,
-                0b001100 => Comp::D,
-                0b110000 => Comp::A,
-                0b001101 => Comp::NotD,
-                0b110001 => Comp::NotA,
-                0b001111 => Comp::MinusD,
-                0b110011 => Comp::MinusA,
-                0b011111 => Comp::DPlus1,
-                0b110111 => Comp::APlus1,
-                0b001110 => Comp::DMinus1,
-                0b110010 => Comp::AMinus1,
-                0b000010 => Comp::DPlusA,
-                0b010011 => Comp::DMinusA,
-                0b000111 => Comp::AMinusD,
-                0b000000 => Comp::DAndA,
-                0b010101 => Comp::DOrA,
-                _ => return Err(err("Unrecognised comp instruction")),
-            };
+            let comp = Comp::from_bits(((word.0 >> 6) & 0b111111) as u8);
             let dest = Dest {
                 a: word.bit(10),
                 d: word.bit(11),
@@ -122,7 +344,7 @@ impl TryFrom<HackWord> for Instruction {
                 dest,
                 jump,
             }
-        })
+        }
     }
 }
 
@@ -140,26 +362,7 @@ impl From<Instruction> for HackWord {
                 if should_deref {
                     word |= 0b0001_0000_0000_0000;
                 }
-                word |= match comp {
-                    Comp::Zero => 0b101010,
-                    Comp::One => 0b111111,
-                    Comp::MinusOne => 0b111010,
-                    Comp::D => 0b001100,
-                    Comp::A => 0b110000,
-                    Comp::NotD => 0b001101,
-                    Comp::NotA => 0b110001,
-                    Comp::MinusD => 0b001111,
-                    Comp::MinusA => 0b110011,
-                    Comp::DPlus1 => 0b011111,
-                    Comp::APlus1 => 0b110111,
-                    Comp::DMinus1 => 0b001110,
-                    Comp::AMinus1 => 0b110010,
-                    Comp::DPlusA => 0b000010,
-                    Comp::DMinusA => 0b010011,
-                    Comp::AMinusD => 0b000111,
-                    Comp::DAndA => 0b000000,
-                    Comp::DOrA => 0b010101,
-                } << 6;
+                word |= (comp.bits() as i16) << 6;
                 if dest.a {
                     word |= 0b0000_0000_0010_0000;
                 }
@@ -325,7 +528,7 @@ mod tests {
         ] {
             let word = HackWord(input);
 
-            let ins: Instruction = word.try_into().expect("Expect conversion");
+            let ins: Instruction = word.into();
 
             assert_eq!(ins, expected);
 
@@ -334,4 +537,141 @@ mod tests {
             assert_eq!(back, word)
         }
     }
+
+    #[test]
+    fn alu_documented_computations() {
+        for (d, a) in [(0, 0), (5, 3), (-7, 12), (1, -1)] {
+            let (dw, aw) = (HackWord(d), HackWord(a));
+            for (comp, expected) in [
+                (Comp::Zero, 0),
+                (Comp::One, 1),
+                (Comp::MinusOne, -1),
+                (Comp::D, d),
+                (Comp::A, a),
+                (Comp::NotD, !d),
+                (Comp::NotA, !a),
+                (Comp::MinusD, -d),
+                (Comp::MinusA, -a),
+                (Comp::DPlus1, d + 1),
+                (Comp::APlus1, a + 1),
+                (Comp::DMinus1, d - 1),
+                (Comp::AMinus1, a - 1),
+                (Comp::DPlusA, d + a),
+                (Comp::DMinusA, d - a),
+                (Comp::AMinusD, a - d),
+                (Comp::DAndA, d & a),
+                (Comp::DOrA, d | a),
+            ] {
+                assert_eq!(
+                    comp.compute(dw, aw),
+                    HackWord(expected),
+                    "{comp:?} D={d} A={a}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shortcuts_agree_with_the_control_bits() {
+        // the second input is A or M alike, so one spread of values covers both
+        let values = [0, 1, -1, 2, 7, -7, 0x5555, -0x5556, i16::MAX, i16::MIN];
+        for bits in 0..64 {
+            let comp = Comp::from_bits(bits);
+            for d in values {
+                for a in values {
+                    let (d, a) = (HackWord(d), HackWord(a));
+                    assert_eq!(
+                        comp.compute(d, a),
+                        comp.compute_bits(d, a),
+                        "{comp:?} D={d} A={a}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn alu_undocumented_computations() {
+        let (d, a) = (0b1100, 0b1010);
+        for (bits, expected) in [
+            (0b000001, !(d & a)),
+            (0b000011, !(d + a)),
+            (0b010100, !d & !a),
+            (0b100000, 0),
+            (0b001000, 0),
+            (0b000110, d + !a),
+        ] {
+            let comp = Comp::from_bits(bits);
+            assert_eq!(comp.mnemonic(), None);
+            assert_eq!(comp.compute(HackWord(d), HackWord(a)), HackWord(expected));
+        }
+    }
+
+    #[test]
+    fn every_comp_round_trips_through_asm() {
+        for bits in 0..64 {
+            let comp = Comp::from_bits(bits);
+            for should_deref in [false, true] {
+                let asm = comp.to_asm(should_deref);
+                let parsed = Comp::parse_asm(&asm).expect("Expected parse");
+
+                assert_eq!(parsed, (comp, should_deref), "{asm}");
+            }
+        }
+    }
+
+    #[test]
+    fn display_canonical_asm() {
+        for (bits, asm) in [
+            ("0000000001111011", "@123"),
+            ("1111110111101001", "AM=M+1;JGT"),
+            ("1110001100001000", "M=D"),
+            ("1110101010000111", "0;JMP"),
+            ("1111000010111000", "AMD=D+M"),
+            ("1110000001010000", "D=%0000001"),
+        ] {
+            let word: HackWord = bits.parse().unwrap();
+            assert_eq!(Instruction::from(word).to_string(), asm);
+        }
+        let dest = Dest {
+            a: true,
+            d: true,
+            m: false,
+        };
+        assert_eq!(dest.to_string(), "AD");
+        assert_eq!(Jump::Null.to_string(), "");
+        assert_eq!(Jump::JLE.to_string(), "JLE");
+    }
+
+    #[test]
+    fn alternate_format_shows_bits() {
+        let word = "1111110111101001".parse::<HackWord>().unwrap();
+        let ins = Instruction::from(word);
+
+        assert_eq!(ins.to_string(), "AM=M+1;JGT");
+        assert_eq!(
+            format!("{ins:#}"),
+            "AM=M+1;JGT (a=1 zx=1 nx=1 zy=0 ny=1 f=1 no=1)"
+        );
+        assert_eq!(format!("{:#}", Instruction::A(123)), "@123");
+        assert_eq!(
+            format!("{:#}", Comp::DPlus1),
+            "D+1 (zx=0 nx=1 zy=1 ny=1 f=1 no=1)"
+        );
+        assert_eq!(
+            format!("{:#}", Comp::from_bits(0b000001)),
+            "%0000001 (zx=0 nx=0 zy=0 ny=0 f=0 no=1)"
+        );
+    }
+
+    #[test]
+    fn every_word_decodes() {
+        for bits in 0..64i16 {
+            let word = HackWord(i16::MIN | 0b0111_0000_0001_0000 | (bits << 6));
+            let ins: Instruction = word.into();
+            let back: HackWord = ins.into();
+
+            assert_eq!(back, word);
+        }
+    }
 }
@@ -1,153 +1,241 @@
 extern crate minifb;
 use std::{
-    path::Path,
-    time::{Duration, Instant},
+    fs::File,
+    io::BufWriter,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
 };
 
-use minifb::{Key, Window, WindowOptions};
-
 use crate::{
-    common::{read_lines, Res},
+    asm::AsmDebug,
+    capture::{CapturePlan, ScreenImage},
+    common::{err, read_lines, Res},
+    devices::{Console, Keyboard, Screen},
     hackword::HackWord,
-    machine::Machine,
+    input::{InputPlayer, InputScript},
+    keymap::KeyMap,
+    machine::{Machine, StopReason},
 };
 
-pub const SCREEN_MEM_START: u16 = 0x4000;
-pub const KB_MEM_SLOT: u16 = 0x6000;
-pub const SCREEN_WIDTH: usize = 512;
-pub const SCREEN_HEIGHT: usize = 256;
-
-pub fn run_io(mut machine: Machine) -> Res<Machine> {
-    let mut buffer: Vec<u32> = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
-
-    let mut window = Window::new(
-        "Test - ESC to exit",
-        SCREEN_WIDTH,
-        SCREEN_HEIGHT,
-        WindowOptions::default(),
-    )?;
-
-    // Limit to max ~60 fps update rate
-    window.limit_update_rate(Some(Duration::from_micros(16600)));
-
-    while window.is_open() {
-        let start = Instant::now();
-        while (Instant::now() - start).as_millis() < 100 {
-            update_keyboard(&window, &mut machine);
-            if !machine.step()? {
-                return Ok(machine);
-            }
+mod terminal;
+mod window;
+mod worker;
+
+pub use crate::devices::{KB_MEM_SLOT, SCREEN_HEIGHT, SCREEN_MEM_START, SCREEN_WIDTH};
+pub use terminal::*;
+pub use window::*;
+pub use worker::{Clock, Command, Inspection, ScreenUpdate, Status};
+
+use worker::{Published, Worker};
+
+/// Settings for [`run_io`]
+#[derive(Clone, Debug)]
+pub struct IoConfig {
+    /// Where Ctrl+S saves a snapshot, and Ctrl+L loads one from
+    pub snapshot_path: PathBuf,
+    /// Keyboard input to play instead of the live keyboard, until the script runs out
+    pub input: Option<InputScript>,
+    /// Where to save the live keyboard input as a script when the window closes
+    pub record_input: Option<PathBuf>,
+    pub keymap: KeyMap,
+    /// Draw in the terminal rather than opening a window
+    pub terminal: Option<TerminalStyle>,
+    pub window: WindowStyle,
+    /// Symbols for the window's inspector to name RAM and ROM addresses with
+    pub debug: Option<AsmDebug>,
+    pub clock: Clock,
+    /// How often the worker publishes the screen
+    pub fps: u32,
+}
+
+impl Default for IoConfig {
+    fn default() -> Self {
+        Self {
+            snapshot_path: PathBuf::from("hack.snap"),
+            input: None,
+            record_input: None,
+            keymap: KeyMap::default(),
+            terminal: None,
+            window: WindowStyle::default(),
+            debug: None,
+            clock: Clock::default(),
+            fps: 60,
         }
+    }
+}
+
+/// Commands a frontend can send besides keys
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Hotkey {
+    SaveSnapshot,
+    LoadSnapshot,
+    /// Pause or resume
+    Pause,
+    /// Run one instruction while paused
+    Step,
+    /// Double or halve the clock
+    Faster,
+    Slower,
+}
+
+/// Somewhere to show a running machine and take keyboard input from
+pub trait Frontend {
+    /// Whether to keep running
+    fn is_open(&self) -> bool;
 
-        write_to_screen(&machine, &mut buffer);
-        window.update_with_buffer(&buffer, SCREEN_WIDTH, SCREEN_HEIGHT)?;
+    /// The code of the key held down, or 0
+    fn key(&mut self) -> Res<HackWord>;
+
+    /// Shows the screen's changed words and the console's output when they've changed, and
+    /// how the emulation is going. When nothing has changed the frame can be skipped.
+    fn present(
+        &mut self,
+        screen: Option<&ScreenUpdate>,
+        console: Option<&str>,
+        status: &Status,
+    ) -> Res;
+
+    /// A hotkey pressed since the last call
+    fn hotkey(&mut self) -> Res<Option<Hotkey>>;
+
+    /// The RAM addresses to show the machine's state alongside, if any
+    fn inspect_range(&self) -> Option<Range<u16>> {
+        None
     }
 
-    Ok(machine)
+    /// Takes the machine's state for the next [`present`](Frontend::present) to show
+    fn inspect(&mut self, _inspection: Inspection) {}
+}
+
+/// Runs in a window, or the terminal if the config asks for it, until it's closed
+pub fn run_io(machine: Machine, config: &IoConfig) -> Res<Machine> {
+    let console = machine.device::<Console>().is_some();
+    match config.terminal {
+        Some(style) => run_frontend(machine, config, &mut Terminal::new(style)?),
+        None => run_frontend(machine, config, &mut Window::new(config, console)?),
+    }
 }
 
-fn write_to_screen(machine: &Machine, buffer: &mut Vec<u32>) {
-    for row in 0..SCREEN_HEIGHT {
-        for w in 0..(SCREEN_WIDTH / 16) {
-            let location = SCREEN_MEM_START as usize + (row * (SCREEN_WIDTH as usize / 16)) + w;
-            let word = machine.memory[location];
-            for i in 0..16u8 {
-                let col = i as usize + (w * 16);
-                // each word is mapped 'backwards'
-                let pix = if word.bit(15 - i) { 0 } else { 0xFFFFFF };
-                buffer[(row * SCREEN_WIDTH) + col] = pix;
-            }
+/// Runs the machine on a worker thread until the frontend is closed or the program leaves
+/// its ROM. The frontend draws whatever the worker last published, so a slow program
+/// doesn't slow drawing down and a slow frontend doesn't slow the program.
+pub fn run_frontend(
+    mut machine: Machine,
+    config: &IoConfig,
+    frontend: &mut impl Frontend,
+) -> Res<Machine> {
+    if machine.device::<Screen>().is_none() {
+        machine.attach(Screen::default())?;
+    }
+    if machine.device::<Keyboard>().is_none() {
+        machine.attach(Keyboard::default())?;
+    }
+
+    let (commands, receiver) = mpsc::channel();
+    let published = Arc::new(Mutex::new(Published::default()));
+    let worker = Worker::new(machine, config);
+    let handle = {
+        let published = published.clone();
+        thread::spawn(move || worker.run(receiver, published))
+    };
+
+    // sending fails once the worker has finished, which the loop notices below
+    let mut key = HackWord(0);
+    let mut inspecting = None;
+    while frontend.is_open() {
+        let finished = handle.is_finished();
+        let pressed = frontend.key()?;
+        if pressed != key {
+            key = pressed;
+            let _ = commands.send(Command::Key(key));
+        }
+
+        let range = frontend.inspect_range();
+        if range != inspecting {
+            inspecting.clone_from(&range);
+            let _ = commands.send(Command::Inspect(range));
+        }
+
+        let (screen, console, inspection, status) = {
+            let mut published = published.lock().unwrap();
+            let (screen, console) = (published.screen.take(), published.console.take());
+            (
+                screen,
+                console,
+                published.inspection.take(),
+                published.status,
+            )
+        };
+        if let Some(inspection) = inspection {
+            frontend.inspect(inspection);
+        }
+        frontend.present(screen.as_ref(), console.as_deref(), &status)?;
+        if let Some(hotkey) = frontend.hotkey()? {
+            let _ = commands.send(Command::Hotkey(hotkey));
         }
+        if finished {
+            break;
+        }
+    }
+
+    let _ = commands.send(Command::Quit);
+    let (machine, recorder) = handle
+        .join()
+        .map_err(|_| err("The emulation thread panicked"))?;
+    if let (Some(recorder), Some(path)) = (recorder, &config.record_input) {
+        recorder.write(BufWriter::new(File::create(path)?))?;
     }
+    Ok(machine)
 }
 
-fn update_keyboard(window: &Window, machine: &mut Machine) {
-    let code: i16 = window
-        .get_keys()
-        .iter()
-        .find_map(|k| {
-            Some(match k {
-                Key::Key0 => b'0'.into(),
-                Key::Key1 => b'1'.into(),
-                Key::Key2 => b'2'.into(),
-                Key::Key3 => b'3'.into(),
-                Key::Key4 => b'4'.into(),
-                Key::Key5 => b'5'.into(),
-                Key::Key6 => b'6'.into(),
-                Key::Key7 => b'7'.into(),
-                Key::Key8 => b'8'.into(),
-                Key::Key9 => b'9'.into(),
-                Key::A => b'A'.into(),
-                Key::B => b'B'.into(),
-                Key::C => b'C'.into(),
-                Key::D => b'D'.into(),
-                Key::E => b'E'.into(),
-                Key::F => b'F'.into(),
-                Key::G => b'G'.into(),
-                Key::H => b'H'.into(),
-                Key::I => b'I'.into(),
-                Key::J => b'J'.into(),
-                Key::K => b'K'.into(),
-                Key::L => b'L'.into(),
-                Key::M => b'M'.into(),
-                Key::N => b'N'.into(),
-                Key::O => b'O'.into(),
-                Key::P => b'P'.into(),
-                Key::Q => b'Q'.into(),
-                Key::R => b'R'.into(),
-                Key::S => b'S'.into(),
-                Key::T => b'T'.into(),
-                Key::U => b'U'.into(),
-                Key::V => b'V'.into(),
-                Key::W => b'W'.into(),
-                Key::X => b'X'.into(),
-                Key::Y => b'Y'.into(),
-                Key::Z => b'Z'.into(),
-                Key::F1 => 141,
-                Key::F2 => 142,
-                Key::F3 => 143,
-                Key::F4 => 144,
-                Key::F5 => 145,
-                Key::F6 => 146,
-                Key::F7 => 147,
-                Key::F8 => 148,
-                Key::F9 => 149,
-                Key::F10 => 150,
-                Key::F11 => 151,
-                Key::F12 => 152,
-                Key::Escape => 140,
-                Key::Space => b' '.into(),
-                Key::Enter => 128,
-                Key::Up => 131,
-                Key::Down => 133,
-                Key::Left => 130,
-                Key::Right => 132,
-                Key::Backspace => 129,
-                Key::Tab => b'\t'.into(),
-                Key::Home => 134,
-                Key::End => 135,
-                Key::PageUp => 136,
-                Key::PageDown => 137,
-                Key::Insert => 138,
-                Key::Delete => 139,
-                // Key::Grave => b'~'.into(),
-                Key::Minus => b'-'.into(),
-                Key::Equal => b'='.into(),
-                Key::LeftBracket => b'['.into(),
-                Key::RightBracket => b']'.into(),
-                Key::Backslash => b'\\'.into(),
-                Key::Semicolon => b';'.into(),
-                Key::Apostrophe => b'\''.into(),
-                Key::Comma => b','.into(),
-                Key::Period => b'.'.into(),
-                Key::Slash => b'/'.into(),
-                Key::Backquote => b'`'.into(),
-                _ => return None,
-            })
-        })
-        .unwrap_or(0);
-
-    machine.memory[KB_MEM_SLOT as usize] = HackWord(code);
+/// Runs without a window until the program halts or `max_cycles` have run, playing `input`
+/// into the keyboard and saving the screen as `capture` asks
+pub fn run_headless(
+    machine: &mut Machine,
+    max_cycles: Option<u64>,
+    capture: Option<&CapturePlan>,
+    mut input: Option<InputPlayer>,
+) -> Res<StopReason> {
+    if input.is_some() && machine.device::<Keyboard>().is_none() {
+        machine.attach(Keyboard::default())?;
+    }
+
+    let end = max_cycles.map_or(u64::MAX, |max| machine.cycles().saturating_add(max));
+    if let Some(plan) = capture.filter(|plan| plan.at.contains(&machine.cycles())) {
+        plan.save_numbered(machine)?;
+    }
+
+    let reason = loop {
+        if let Some(player) = &mut input {
+            player.apply(machine, 0);
+        }
+        let now = machine.cycles();
+        let next = [
+            capture.and_then(|plan| plan.next_capture(now)),
+            input.as_ref().and_then(InputPlayer::next_cycle),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(end, u64::min);
+
+        if machine.run_with_limit(next - now) == StopReason::Halted {
+            break StopReason::Halted;
+        }
+        let now = machine.cycles();
+        if let Some(plan) = capture.filter(|plan| plan.is_due(now)) {
+            plan.save_numbered(machine)?;
+        }
+        if now >= end {
+            break StopReason::StepLimit;
+        }
+    };
+
+    if let Some(plan) = capture {
+        ScreenImage::capture(machine).save(&plan.path)?;
+    }
+    Ok(reason)
 }
 
 pub fn read_instructions(path: impl AsRef<Path>) -> Res<Vec<HackWord>> {
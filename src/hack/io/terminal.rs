@@ -0,0 +1,368 @@
+//! A frontend drawing the screen in a terminal, for running over SSH or in containers
+//! where no window can be opened.
+
+use std::io::{self, Stdout, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crossterm::{
+    cursor,
+    event::{
+        self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+        PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
+    execute, queue,
+    style::Print,
+    terminal::{self, ClearType},
+};
+
+use super::{Frontend, Hotkey, ScreenUpdate, Status};
+use crate::{
+    capture::ScreenImage,
+    common::Res,
+    devices::{SCREEN_HEIGHT, SCREEN_WIDTH},
+    hackword::HackWord,
+};
+
+/// How long a key counts as held after the terminal last reported it, for terminals that
+/// don't report releases. It's longer than the usual key repeat interval, so a key held
+/// down stays held once it starts repeating.
+const KEY_HOLD: Duration = Duration::from_millis(150);
+/// The shortest time between frames, so a halted program isn't redrawn flat out
+const FRAME_TIME: Duration = Duration::from_millis(16);
+/// The pixel each bit of a braille pattern stands for: the dot numbering runs down the
+/// left column, then the right, then along the bottom row
+#[rustfmt::skip]
+const BRAILLE_DOTS: [(usize, usize); 8] = [(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (1, 2), (0, 3), (1, 3)];
+/// Lines of console output shown under the screen
+const CONSOLE_LINES: usize = 4;
+
+/// How pixels are packed into characters
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum TerminalStyle {
+    /// Braille patterns of 2x4 pixels, taking 256x64 characters
+    #[default]
+    Braille,
+    /// Half blocks of 1x2 pixels, taking 512x128 characters
+    HalfBlock,
+}
+
+impl FromStr for TerminalStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "braille" => Ok(Self::Braille),
+            "half-block" => Ok(Self::HalfBlock),
+            _ => Err(format!(
+                "Unknown terminal style '{s}', try braille or half-block"
+            )),
+        }
+    }
+}
+
+impl TerminalStyle {
+    /// Renders the screen as lines of text, with black pixels drawn in the foreground
+    pub fn render(self, image: &ScreenImage) -> Vec<String> {
+        match self {
+            Self::Braille => (0..SCREEN_HEIGHT / 4)
+                .map(|row| {
+                    (0..SCREEN_WIDTH / 2)
+                        .map(|column| braille(image, column * 2, row * 4))
+                        .collect()
+                })
+                .collect(),
+            Self::HalfBlock => (0..SCREEN_HEIGHT / 2)
+                .map(|row| {
+                    (0..SCREEN_WIDTH)
+                        .map(
+                            |x| match (image.pixel(x, row * 2), image.pixel(x, row * 2 + 1)) {
+                                (false, false) => ' ',
+                                (true, false) => '▀',
+                                (false, true) => '▄',
+                                (true, true) => '█',
+                            },
+                        )
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The braille pattern for the 2x4 pixels from (`left`, `top`)
+fn braille(image: &ScreenImage, left: usize, top: usize) -> char {
+    let bits = BRAILLE_DOTS
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, (x, y))| {
+            bits | (image.pixel(left + x, top + y) as u32) << i
+        });
+    char::from_u32(0x2800 + bits).unwrap_or(' ')
+}
+
+/// The Hack character set's code for a key the terminal reports
+fn key_code(code: KeyCode) -> Option<HackWord> {
+    let code = match code {
+        KeyCode::Char(c @ ' '..='~') => c as i16,
+        KeyCode::Tab => b'\t'.into(),
+        KeyCode::Enter => 128,
+        KeyCode::Backspace => 129,
+        KeyCode::Left => 130,
+        KeyCode::Up => 131,
+        KeyCode::Right => 132,
+        KeyCode::Down => 133,
+        KeyCode::Home => 134,
+        KeyCode::End => 135,
+        KeyCode::PageUp => 136,
+        KeyCode::PageDown => 137,
+        KeyCode::Insert => 138,
+        KeyCode::Delete => 139,
+        KeyCode::Esc => 140,
+        KeyCode::F(n @ 1..=12) => 140 + n as i16,
+        _ => return None,
+    };
+    Some(HackWord(code))
+}
+
+/// Draws the screen with Unicode characters and reads keys in raw mode. Ctrl+C or Ctrl+Q
+/// quits, since raw mode stops Ctrl+C interrupting; the other hotkeys are as in the window.
+pub struct Terminal {
+    out: Stdout,
+    style: TerminalStyle,
+    /// What's on the terminal, so only changed lines are redrawn
+    lines: Vec<String>,
+    /// The last screen and console output, for redrawing after a resize
+    screen: Vec<HackWord>,
+    console: Option<String>,
+    status: String,
+    open: bool,
+    key: HackWord,
+    pressed_at: Instant,
+    /// Whether the terminal reports key releases, rather than them being guessed
+    releases: bool,
+    hotkey: Option<Hotkey>,
+    last_frame: Instant,
+}
+
+impl Terminal {
+    pub fn new(style: TerminalStyle) -> Res<Self> {
+        terminal::enable_raw_mode()?;
+        let releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        let mut out = io::stdout();
+        execute!(
+            out,
+            terminal::EnterAlternateScreen,
+            cursor::Hide,
+            terminal::Clear(ClearType::All)
+        )?;
+        if releases {
+            execute!(
+                out,
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+
+        Ok(Self {
+            out,
+            style,
+            lines: Vec::new(),
+            screen: Vec::new(),
+            console: None,
+            status: String::new(),
+            open: true,
+            key: HackWord(0),
+            pressed_at: Instant::now(),
+            releases,
+            hotkey: None,
+            last_frame: Instant::now(),
+        })
+    }
+
+    fn read_events(&mut self) -> Res {
+        while event::poll(Duration::ZERO)? {
+            match event::read()? {
+                Event::Key(key) => self.handle_key(key),
+                Event::Resize(..) => {
+                    self.lines.clear();
+                    queue!(self.out, terminal::Clear(ClearType::All))?;
+                }
+                _ => (),
+            }
+        }
+        if !self.releases && self.pressed_at.elapsed() > KEY_HOLD {
+            self.key = HackWord(0);
+        }
+        Ok(())
+    }
+
+    fn handle_key(&mut self, event: KeyEvent) {
+        if event.modifiers.contains(KeyModifiers::CONTROL) {
+            if event.kind == KeyEventKind::Press {
+                match event.code {
+                    KeyCode::Char('c' | 'q') => self.open = false,
+                    KeyCode::Char('s') => self.hotkey = Some(Hotkey::SaveSnapshot),
+                    KeyCode::Char('l') => self.hotkey = Some(Hotkey::LoadSnapshot),
+                    KeyCode::Char('p') => self.hotkey = Some(Hotkey::Pause),
+                    KeyCode::Char('n') => self.hotkey = Some(Hotkey::Step),
+                    KeyCode::Up => self.hotkey = Some(Hotkey::Faster),
+                    KeyCode::Down => self.hotkey = Some(Hotkey::Slower),
+                    _ => (),
+                }
+            }
+            return;
+        }
+
+        let Some(code) = key_code(event.code) else {
+            return;
+        };
+        match event.kind {
+            KeyEventKind::Press | KeyEventKind::Repeat => {
+                self.key = code;
+                self.pressed_at = Instant::now();
+            }
+            KeyEventKind::Release if self.key == code => self.key = HackWord(0),
+            KeyEventKind::Release => (),
+        }
+    }
+
+    fn width(&self) -> usize {
+        self.lines.first().map_or(0, |line| line.chars().count())
+    }
+
+    fn draw_console(&mut self, text: &str) -> Res {
+        let width = self.width();
+        let lines: Vec<&str> = text.rsplit('\n').take(CONSOLE_LINES).collect();
+        for (i, line) in lines.iter().rev().enumerate() {
+            let line: String = line
+                .chars()
+                .map(|c| if c == '\t' { ' ' } else { c })
+                .take(width)
+                .collect();
+            queue!(
+                self.out,
+                cursor::MoveTo(0, (self.lines.len() + i) as u16),
+                Print(format!("{line:width$}"))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Frontend for Terminal {
+    fn is_open(&self) -> bool {
+        self.open
+    }
+
+    fn key(&mut self) -> Res<HackWord> {
+        self.read_events()?;
+        Ok(self.key)
+    }
+
+    fn present(
+        &mut self,
+        screen: Option<&ScreenUpdate>,
+        console: Option<&str>,
+        status: &Status,
+    ) -> Res {
+        let resized = self.lines.is_empty();
+        if let Some(screen) = screen {
+            self.screen.clone_from(&screen.words);
+        }
+        if (screen.is_some() || resized) && !self.screen.is_empty() {
+            let lines = self.style.render(&ScreenImage::from_screen(&self.screen));
+            for (row, line) in lines.iter().enumerate() {
+                if self.lines.get(row) != Some(line) {
+                    queue!(self.out, cursor::MoveTo(0, row as u16), Print(line))?;
+                }
+            }
+            self.lines = lines;
+        }
+        if let Some(text) = console {
+            self.console = Some(text.to_owned());
+        }
+        if let Some(text) = self.console.clone() {
+            if console.is_some() || resized {
+                self.draw_console(&text)?;
+            }
+        }
+
+        // the status goes on the line below the console, if there is one
+        let status = status.to_string();
+        if (status != self.status || resized) && !self.lines.is_empty() {
+            let width = self.width();
+            let row = self.lines.len() + self.console.as_ref().map_or(0, |_| CONSOLE_LINES);
+            let line: String = status.chars().take(width).collect();
+            queue!(
+                self.out,
+                cursor::MoveTo(0, row as u16),
+                Print(format!("{line:width$}"))
+            )?;
+            self.status = status;
+        }
+        self.out.flush()?;
+
+        std::thread::sleep(FRAME_TIME.saturating_sub(self.last_frame.elapsed()));
+        self.last_frame = Instant::now();
+        Ok(())
+    }
+
+    fn hotkey(&mut self) -> Res<Option<Hotkey>> {
+        Ok(self.hotkey.take())
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        // there's nowhere to report failures to restore the terminal
+        if self.releases {
+            let _ = execute!(self.out, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(self.out, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{devices::SCREEN_MEM_START, machine::MEMORY_SIZE};
+
+    fn image() -> ScreenImage {
+        let mut memory = [HackWord::zero(); MEMORY_SIZE];
+        // the leftmost two pixels of the first row, and the first pixel of the second
+        memory[SCREEN_MEM_START as usize] = HackWord(0b11);
+        memory[SCREEN_MEM_START as usize + 32] = HackWord(0b1);
+        ScreenImage::from_memory(&memory)
+    }
+
+    #[test]
+    fn braille() {
+        let lines = TerminalStyle::Braille.render(&image());
+
+        assert_eq!(lines.len(), 64);
+        assert!(lines.iter().all(|line| line.chars().count() == 256));
+        // dots 1, 2 and 4
+        assert!(lines[0].starts_with("\u{280B}\u{2800}"));
+    }
+
+    #[test]
+    fn half_blocks() {
+        let lines = TerminalStyle::HalfBlock.render(&image());
+
+        assert_eq!(lines.len(), 128);
+        assert!(lines[0].starts_with("█▀ "));
+        assert_eq!(lines[1].trim_end(), "");
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(key_code(KeyCode::Char('a')), Some(HackWord(97)));
+        assert_eq!(key_code(KeyCode::Char('~')), Some(HackWord(126)));
+        assert_eq!(key_code(KeyCode::Enter), Some(HackWord(128)));
+        assert_eq!(key_code(KeyCode::F(12)), Some(HackWord(152)));
+        assert_eq!(key_code(KeyCode::Char('é')), None);
+        assert_eq!("half-block".parse(), Ok(TerminalStyle::HalfBlock));
+        assert!("sixel".parse::<TerminalStyle>().is_err());
+    }
+}
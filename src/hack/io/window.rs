@@ -0,0 +1,391 @@
+//! The minifb window frontend.
+
+use std::ops::Range;
+use std::time::Duration;
+
+use minifb::{Key, KeyRepeat, MouseMode, WindowOptions};
+
+use super::{Frontend, Hotkey, Inspection, IoConfig, ScreenUpdate, Status};
+use crate::{
+    common::Res,
+    devices::{DirtyWords, SCREEN_HEIGHT, SCREEN_MEM_START, SCREEN_WIDTH, SCREEN_WORDS},
+    font::{self, GLYPH_HEIGHT, GLYPH_WIDTH},
+    hackword::HackWord,
+    keymap::KeyMap,
+};
+
+mod inspector;
+
+use inspector::Inspector;
+
+/// The console and inspector panels shown to the right of the screen
+const PANEL_WIDTH: usize = 256;
+const PANEL_MARGIN: usize = 4;
+const PANEL_BACKGROUND: u32 = 0x202020;
+const PANEL_TEXT: u32 = 0xE0E0E0;
+const PANEL_COLUMNS: usize = (PANEL_WIDTH - 2 * PANEL_MARGIN) / GLYPH_WIDTH;
+const PANEL_ROWS: usize = (SCREEN_HEIGHT - 2 * PANEL_MARGIN) / GLYPH_HEIGHT;
+/// RAM rows the inspector scrolls by for each notch of the mouse wheel
+const SCROLL_ROWS: isize = 3;
+
+/// How the window draws the screen
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct WindowStyle {
+    /// How many window pixels across and down each screen pixel takes
+    pub scale: usize,
+    /// The colours of black and white pixels, as 0xRRGGBB
+    pub foreground: u32,
+    pub background: u32,
+}
+
+impl Default for WindowStyle {
+    fn default() -> Self {
+        Self {
+            scale: 1,
+            foreground: 0x000000,
+            background: 0xFFFFFF,
+        }
+    }
+}
+
+/// Parses a colour written as hex `RRGGBB`, optionally after a `#`
+pub fn parse_colour(s: &str) -> Result<u32, String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    match u32::from_str_radix(hex, 16) {
+        Ok(colour) if hex.len() == 6 => Ok(colour),
+        _ => Err(format!("Expected a colour like #33ff66, not '{s}'")),
+    }
+}
+
+/// Shows the screen in a window, with a panel for the console if there is one. Ctrl+I opens
+/// an inspector panel showing the registers and RAM, which the mouse wheel scrolls.
+pub struct Window {
+    window: minifb::Window,
+    style: WindowStyle,
+    /// One pixel per screen pixel, before scaling
+    buffer: Vec<u32>,
+    scaled: Vec<u32>,
+    width: usize,
+    keymap: KeyMap,
+    title: String,
+    /// The screen and console as last drawn, for drawing again when the window changes size
+    screen: Vec<HackWord>,
+    console: Option<String>,
+    inspector: Inspector,
+    inspecting: bool,
+    inspection: Option<Inspection>,
+    /// The inspection or the inspector's scrolling changed since the last frame
+    inspection_changed: bool,
+}
+
+impl Window {
+    pub fn new(config: &IoConfig, console: bool) -> Res<Self> {
+        let width = SCREEN_WIDTH + console as usize * PANEL_WIDTH;
+        let mut window = Self {
+            window: open(width, config.window.scale)?,
+            style: config.window,
+            buffer: Vec::new(),
+            scaled: Vec::new(),
+            width: 0,
+            keymap: config.keymap.clone(),
+            title: String::new(),
+            screen: vec![HackWord(0); SCREEN_WORDS],
+            console: console.then(String::new),
+            inspector: Inspector::new(config.debug.as_ref(), PANEL_ROWS),
+            inspecting: false,
+            inspection: None,
+            inspection_changed: false,
+        };
+        window.resize()?;
+        Ok(window)
+    }
+
+    /// Makes the window fit its panels and draws everything again
+    fn resize(&mut self) -> Res {
+        let panels = self.console.is_some() as usize + self.inspecting as usize;
+        let width = SCREEN_WIDTH + panels * PANEL_WIDTH;
+        if width != self.width {
+            if self.width != 0 {
+                self.window = open(width, self.style.scale)?;
+                self.title.clear();
+            }
+            self.width = width;
+            self.buffer = vec![0; width * SCREEN_HEIGHT];
+        }
+
+        let screen = ScreenUpdate {
+            words: std::mem::take(&mut self.screen),
+            dirty: DirtyWords::all(),
+        };
+        self.draw_screen(&screen);
+        self.screen = screen.words;
+        if let Some(text) = &self.console {
+            draw_console(text, &mut self.buffer, self.width);
+        }
+        self.draw_inspector();
+        Ok(())
+    }
+
+    fn ctrl(&self) -> bool {
+        self.window.is_key_down(Key::LeftCtrl) || self.window.is_key_down(Key::RightCtrl)
+    }
+
+    /// Draws the words of the screen that have changed
+    fn draw_screen(&mut self, screen: &ScreenUpdate) {
+        for offset in screen.dirty.iter() {
+            let (row, w) = (offset / (SCREEN_WIDTH / 16), offset % (SCREEN_WIDTH / 16));
+            let word = screen.words[offset];
+            for i in 0..16u8 {
+                let col = i as usize + (w * 16);
+                // each word is mapped 'backwards'
+                let pix = if word.bit(15 - i) {
+                    self.style.foreground
+                } else {
+                    self.style.background
+                };
+                self.buffer[(row * self.width) + col] = pix;
+            }
+        }
+    }
+
+    fn draw_inspector(&mut self) {
+        if let (true, Some(inspection)) = (self.inspecting, &self.inspection) {
+            let lines = self.inspector.text(inspection);
+            let left = self.width - PANEL_WIDTH;
+            draw_panel(&lines, &mut self.buffer, self.width, left);
+        }
+    }
+
+    /// Which screen word and bit the mouse is over, if it's over the screen
+    fn hover(&self) -> Option<String> {
+        let (x, y) = self.window.get_mouse_pos(MouseMode::Discard)?;
+        let (x, y) = (x as usize / self.style.scale, y as usize / self.style.scale);
+        (x < SCREEN_WIDTH && y < SCREEN_HEIGHT).then(|| hover_text(&self.screen, x, y))
+    }
+}
+
+fn open(width: usize, scale: usize) -> Res<minifb::Window> {
+    let mut window = minifb::Window::new(
+        "Hack",
+        width * scale,
+        SCREEN_HEIGHT * scale,
+        WindowOptions::default(),
+    )?;
+
+    // Limit to max ~60 fps update rate
+    window.limit_update_rate(Some(Duration::from_micros(16600)));
+    Ok(window)
+}
+
+impl Frontend for Window {
+    fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+
+    /// Keys held with Ctrl are left for hotkeys
+    fn key(&mut self) -> Res<HackWord> {
+        if self.ctrl() {
+            return Ok(HackWord(0));
+        }
+        let held = self.window.get_keys();
+        let pressed = self.window.get_keys_pressed(KeyRepeat::No);
+        Ok(self.keymap.update(&held, &pressed))
+    }
+
+    fn present(
+        &mut self,
+        screen: Option<&ScreenUpdate>,
+        console: Option<&str>,
+        status: &Status,
+    ) -> Res {
+        let title = match self.hover() {
+            Some(hover) => format!("Hack - {status} - {hover}"),
+            None => format!("Hack - {status}"),
+        };
+        if title != self.title {
+            self.window.set_title(&title);
+            self.title = title;
+        }
+
+        let mut changed = screen.is_some() || console.is_some();
+        if let Some(screen) = screen {
+            self.draw_screen(screen);
+            self.screen.clone_from(&screen.words);
+        }
+        if let (Some(text), Some(kept)) = (console, &mut self.console) {
+            text.clone_into(kept);
+            draw_console(text, &mut self.buffer, self.width);
+        }
+        if let Some((_, wheel)) = self.window.get_scroll_wheel().filter(|_| self.inspecting) {
+            self.inspector
+                .scroll(-(wheel.signum() as isize) * SCROLL_ROWS);
+        }
+        if std::mem::take(&mut self.inspection_changed) {
+            self.draw_inspector();
+            changed = true;
+        }
+
+        // nothing to draw, but the window still needs its events handled
+        if !changed {
+            self.window.update();
+            return Ok(());
+        }
+        let (width, scale) = (self.width, self.style.scale);
+        if scale == 1 {
+            self.window
+                .update_with_buffer(&self.buffer, width, SCREEN_HEIGHT)?;
+        } else {
+            scale_buffer(&self.buffer, width, scale, &mut self.scaled);
+            self.window
+                .update_with_buffer(&self.scaled, width * scale, SCREEN_HEIGHT * scale)?;
+        }
+        Ok(())
+    }
+
+    /// Ctrl+S and Ctrl+L save and load snapshots, Ctrl+P pauses, Ctrl+N steps while paused
+    /// and Ctrl+Up and Ctrl+Down change the clock. Ctrl+I toggles the inspector, which
+    /// Ctrl+PageUp and Ctrl+PageDown scroll.
+    fn hotkey(&mut self) -> Res<Option<Hotkey>> {
+        if !self.ctrl() {
+            return Ok(None);
+        }
+        if self.window.is_key_pressed(Key::I, KeyRepeat::No) {
+            self.inspecting = !self.inspecting;
+            self.inspection = None;
+            self.resize()?;
+        }
+        for (key, rows) in [(Key::PageUp, -1), (Key::PageDown, 1)] {
+            if self.inspecting && self.window.is_key_pressed(key, KeyRepeat::Yes) {
+                let page = self.inspector.range().len() as isize;
+                self.inspector.scroll(rows * page);
+            }
+        }
+
+        const HOTKEYS: [(Key, Hotkey); 6] = [
+            (Key::S, Hotkey::SaveSnapshot),
+            (Key::L, Hotkey::LoadSnapshot),
+            (Key::P, Hotkey::Pause),
+            (Key::N, Hotkey::Step),
+            (Key::Up, Hotkey::Faster),
+            (Key::Down, Hotkey::Slower),
+        ];
+        Ok(HOTKEYS
+            .iter()
+            .find(|(key, _)| self.window.is_key_pressed(*key, KeyRepeat::No))
+            .map(|&(_, hotkey)| hotkey))
+    }
+
+    fn inspect_range(&self) -> Option<Range<u16>> {
+        self.inspecting.then(|| self.inspector.range())
+    }
+
+    fn inspect(&mut self, inspection: Inspection) {
+        if self.inspection.as_ref() != Some(&inspection) {
+            self.inspection = Some(inspection);
+            self.inspection_changed = true;
+        }
+    }
+}
+
+/// Describes the screen word and bit holding the pixel at (`x`, `y`)
+fn hover_text(screen: &[HackWord], x: usize, y: usize) -> String {
+    let offset = y * (SCREEN_WIDTH / 16) + x / 16;
+    // the leftmost pixel of a word is its least significant bit
+    let bit = (x % 16) as u8;
+    let value = screen[offset].bit(15 - bit) as u8;
+    let address = SCREEN_MEM_START as usize + offset;
+    format!("({x}, {y}) is RAM[{address}] bit {bit} = {value}")
+}
+
+/// Copies `buffer` into `out`, with each pixel made `scale` pixels across and down
+fn scale_buffer(buffer: &[u32], width: usize, scale: usize, out: &mut Vec<u32>) {
+    out.clear();
+    for row in buffer.chunks(width) {
+        let start = out.len();
+        for &pixel in row {
+            out.extend(std::iter::repeat_n(pixel, scale));
+        }
+        for _ in 1..scale {
+            out.extend_from_within(start..start + width * scale);
+        }
+    }
+}
+
+/// Draws the end of the console's output into its panel, wrapping long lines
+fn draw_console(text: &str, buffer: &mut [u32], stride: usize) {
+    // the last PANEL_ROWS wrapped lines, newest first
+    let mut rows: Vec<&str> = Vec::new();
+    for line in text.rsplit('\n') {
+        let wrapped: Vec<&str> = match line.len() {
+            0 => vec![""],
+            _ => (0..line.len())
+                .step_by(PANEL_COLUMNS)
+                .map(|start| &line[start..(start + PANEL_COLUMNS).min(line.len())])
+                .collect(),
+        };
+        rows.extend(wrapped.into_iter().rev());
+        if rows.len() >= PANEL_ROWS {
+            break;
+        }
+    }
+    rows.truncate(PANEL_ROWS);
+    rows.reverse();
+    draw_panel(&rows, buffer, stride, SCREEN_WIDTH);
+}
+
+/// Fills the panel starting at column `left` and writes `lines` into it
+fn draw_panel(lines: &[impl AsRef<str>], buffer: &mut [u32], stride: usize, left: usize) {
+    for row in buffer.chunks_mut(stride) {
+        row[left..left + PANEL_WIDTH].fill(PANEL_BACKGROUND);
+    }
+    for (i, text) in lines.iter().take(PANEL_ROWS).enumerate() {
+        let top = PANEL_MARGIN + i * GLYPH_HEIGHT;
+        for (column, c) in text.as_ref().chars().take(PANEL_COLUMNS).enumerate() {
+            let left = left + PANEL_MARGIN + column * GLYPH_WIDTH;
+            let glyph = font::glyph(if c == '\t' { ' ' } else { c });
+            for y in 0..GLYPH_HEIGHT {
+                for x in 0..GLYPH_WIDTH {
+                    if font::pixel(glyph, x, y) {
+                        buffer[(top + y) * stride + left + x] = PANEL_TEXT;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaling() {
+        let mut scaled = Vec::new();
+        scale_buffer(&[1, 2, 3, 4], 2, 2, &mut scaled);
+
+        assert_eq!(scaled, [1, 1, 2, 2, 1, 1, 2, 2, 3, 3, 4, 4, 3, 3, 4, 4]);
+    }
+
+    #[test]
+    fn hovering() {
+        let mut screen = vec![HackWord(0); SCREEN_WORDS];
+        screen[33] = HackWord(0b100);
+
+        assert_eq!(
+            hover_text(&screen, 18, 1),
+            "(18, 1) is RAM[16417] bit 2 = 1"
+        );
+        assert_eq!(
+            hover_text(&screen, 17, 1),
+            "(17, 1) is RAM[16417] bit 1 = 0"
+        );
+    }
+
+    #[test]
+    fn colours() {
+        assert_eq!(parse_colour("#33ff66"), Ok(0x33FF66));
+        assert_eq!(parse_colour("000000"), Ok(0));
+        assert!(parse_colour("#fff").is_err());
+        assert!(parse_colour("green").is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! The window's side panel showing the registers, the current instruction and some RAM.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::{
+    asm::{AsmDebug, SourceIndex},
+    instruction::Instruction,
+    io::Inspection,
+    machine::MEMORY_SIZE,
+};
+
+/// Lines of registers and instruction above the RAM view
+const HEADER_LINES: usize = 5;
+/// Room for the names of a RAM word's variables
+const NAME_WIDTH: usize = 14;
+
+/// Lays out an [`Inspection`] as lines of text, naming addresses with the program's symbols
+pub(super) struct Inspector {
+    /// RAM addresses and the variables stored at them
+    ram_names: BTreeMap<u16, Vec<String>>,
+    index: SourceIndex,
+    /// The first address in the RAM view
+    top: u16,
+    /// How many lines the panel has room for
+    lines: usize,
+}
+
+impl Inspector {
+    pub fn new(debug: Option<&AsmDebug>, lines: usize) -> Self {
+        Self {
+            ram_names: debug.map(AsmDebug::variables).unwrap_or_default(),
+            index: SourceIndex::new(debug),
+            top: 0,
+            lines,
+        }
+    }
+
+    fn ram_rows(&self) -> usize {
+        self.lines.saturating_sub(HEADER_LINES)
+    }
+
+    /// The addresses the RAM view shows
+    pub fn range(&self) -> Range<u16> {
+        self.top..(self.top as usize + self.ram_rows()).min(MEMORY_SIZE) as u16
+    }
+
+    /// Moves the RAM view by `rows`, which are negative to scroll up
+    pub fn scroll(&mut self, rows: isize) {
+        let last = MEMORY_SIZE.saturating_sub(self.ram_rows());
+        self.top = (self.top as isize + rows).clamp(0, last as isize) as u16;
+    }
+
+    pub fn text(&self, inspection: &Inspection) -> Vec<String> {
+        let pc = match self.index.label(inspection.pc) {
+            Some((label, 0)) => format!("{} ({label})", inspection.pc),
+            Some((label, offset)) => format!("{} ({label}+{offset})", inspection.pc),
+            None => inspection.pc.to_string(),
+        };
+        let instruction = match inspection.instruction {
+            Some(word) => Instruction::from(word).to_string(),
+            None => "end of program".into(),
+        };
+
+        let mut lines = vec![
+            format!("A  = {}", inspection.a.describe()),
+            format!("D  = {}", inspection.d.describe()),
+            format!("PC = {pc}"),
+            format!("     {instruction}"),
+            String::new(),
+        ];
+        for (i, &value) in inspection.ram.iter().enumerate() {
+            let address = inspection.ram_start + i as u16;
+            let names = self
+                .ram_names
+                .get(&address)
+                .map(|names| names.join(","))
+                .unwrap_or_default();
+            let names: String = names.chars().take(NAME_WIDTH).collect();
+            lines.push(format!("{address:>5} {names:<NAME_WIDTH$} {value:>6}"));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::compile_file;
+    use crate::hackword::HackWord;
+
+    #[test]
+    fn panel_text() {
+        let (rom, debug) = compile_file("resources/mult.asm", true).unwrap();
+        let debug = debug.unwrap();
+        let mut inspector = Inspector::new(Some(&debug), 9);
+        assert_eq!(inspector.range(), 0..4);
+
+        let inspection = Inspection {
+            a: HackWord(16),
+            d: HackWord(-1),
+            pc: debug.label_address("LOOP").unwrap() + 1,
+            instruction: Some(rom[0]),
+            ram_start: 0,
+            ram: vec![HackWord(3), HackWord(4), HackWord(0), HackWord(0)],
+        };
+        let text = inspector.text(&inspection);
+        assert_eq!(text[0], "A  = 16 (0x0010)");
+        assert_eq!(text[1], "D  = -1 (0xffff)");
+        assert!(text[2].ends_with("(LOOP+1)"), "{}", text[2]);
+        assert_eq!(text[3].trim(), Instruction::from(rom[0]).to_string());
+        assert_eq!(text[5], "    0 R0,SP               3");
+        assert_eq!(text.len(), 9);
+
+        inspector.scroll(-5);
+        assert_eq!(inspector.range(), 0..4);
+        inspector.scroll(1 << 15);
+        assert_eq!(inspector.range(), 32764..32768);
+    }
+}
@@ -0,0 +1,541 @@
+//! Running the machine on its own thread, paced by instruction budgets, so drawing and
+//! emulation don't hold each other up.
+
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{Hotkey, IoConfig};
+use crate::{
+    devices::{Console, DirtyWords, Keyboard, Screen, SCREEN_MEM_START, SCREEN_WORDS},
+    hackword::HackWord,
+    input::{InputPlayer, InputRecorder},
+    machine::{Machine, StopReason, MEMORY_SIZE},
+};
+
+/// Instructions run between looks at the time when the clock is unlimited
+const UNLIMITED_CHUNK: u64 = 1 << 14;
+/// The fastest clock the speed hotkeys step through before going unlimited
+const MAX_CLOCK: u64 = 1 << 30;
+/// How often the measured speed is updated
+const RATE_PERIOD: Duration = Duration::from_secs(1);
+/// How long an input script's frame lasts, whatever the redraw rate
+const INPUT_FRAME: Duration = Duration::from_millis(100);
+
+/// How many instructions to run a second
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum Clock {
+    /// As fast as the host can manage
+    #[default]
+    Unlimited,
+    PerSecond(u64),
+}
+
+impl FromStr for Clock {
+    type Err = String;
+
+    /// Parses `unlimited` or a number, optionally ending in `k` or `M`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unlimited" {
+            return Ok(Self::Unlimited);
+        }
+        let (digits, scale) = match s.strip_suffix('k') {
+            Some(digits) => (digits, 1_000),
+            None => match s.strip_suffix('M') {
+                Some(digits) => (digits, 1_000_000),
+                None => (s, 1),
+            },
+        };
+        let rate = digits.parse::<u64>().ok().filter(|&n| n > 0);
+        match rate.and_then(|n| n.checked_mul(scale)) {
+            Some(n) => Ok(Self::PerSecond(n)),
+            None => Err(format!(
+                "Expected 'unlimited' or a positive rate like 500k, not '{s}'"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unlimited => write!(f, "unlimited"),
+            Self::PerSecond(n) => write!(f, "{}/s", Rate(*n)),
+        }
+    }
+}
+
+/// An instruction count, shortened for display
+struct Rate(u64);
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            n if n >= 1_000_000 => write!(f, "{:.1}M", n as f64 / 1e6),
+            n if n >= 1_000 => write!(f, "{:.1}k", n as f64 / 1e3),
+            n => write!(f, "{n}"),
+        }
+    }
+}
+
+/// How the emulation is going, for frontends to show
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct Status {
+    pub cycles: u64,
+    pub clock: Clock,
+    /// Instructions run in the last second
+    pub rate: u64,
+    pub paused: bool,
+    /// The program has reached a halting loop
+    pub halted: bool,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cycle {}, ", self.cycles)?;
+        if self.halted {
+            write!(f, "halted")
+        } else if self.paused {
+            write!(f, "paused")
+        } else {
+            write!(f, "{}/s (clock {})", Rate(self.rate), self.clock)
+        }
+    }
+}
+
+/// Messages from the frontend to the worker
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Command {
+    Key(HackWord),
+    Hotkey(Hotkey),
+    /// Publish the registers and these RAM addresses each frame, or stop with `None`
+    Inspect(Option<Range<u16>>),
+    Quit,
+}
+
+/// The registers and a stretch of RAM, for an inspector to show
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Inspection {
+    pub a: HackWord,
+    pub d: HackWord,
+    pub pc: u16,
+    /// The instruction at `pc`, unless that's past the end of the program
+    pub instruction: Option<HackWord>,
+    /// The address of the first word of `ram`
+    pub ram_start: u16,
+    pub ram: Vec<HackWord>,
+}
+
+/// Screen memory, and the words of it that changed since the frontend last drew it
+#[derive(Clone, Debug)]
+pub struct ScreenUpdate {
+    pub words: Vec<HackWord>,
+    pub dirty: DirtyWords,
+}
+
+/// What the worker has published since the frontend last looked
+#[derive(Debug, Default)]
+pub(super) struct Published {
+    /// The screen, if it has changed
+    pub screen: Option<ScreenUpdate>,
+    /// The console's output, if it has changed
+    pub console: Option<String>,
+    /// What the frontend asked to inspect
+    pub inspection: Option<Inspection>,
+    pub status: Status,
+}
+
+/// Owns the machine while it runs, taking commands and publishing frames
+pub(super) struct Worker {
+    machine: Machine,
+    clock: Clock,
+    fps: u32,
+    paused: bool,
+    halted: bool,
+    /// The program has left its ROM, so there's nothing more to run
+    finished: bool,
+    /// A whole frame's worth of the screen needs publishing, as after loading a snapshot
+    redraw: bool,
+    snapshot_path: PathBuf,
+    inspect: Option<Range<u16>>,
+    player: Option<InputPlayer>,
+    recorder: Option<InputRecorder>,
+    /// Time spent running rather than paused, which paces an input script's frames
+    running: Duration,
+    /// Instructions owed by a slow clock, carried between frames
+    owed: f64,
+    rate: u64,
+    rate_start: (Instant, u64),
+}
+
+impl Worker {
+    pub fn new(machine: Machine, config: &IoConfig) -> Self {
+        let cycles = machine.cycles();
+        Self {
+            machine,
+            clock: config.clock,
+            fps: config.fps.max(1),
+            paused: false,
+            halted: false,
+            finished: false,
+            redraw: true,
+            snapshot_path: config.snapshot_path.clone(),
+            inspect: None,
+            player: config.input.as_ref().map(InputPlayer::new),
+            recorder: config
+                .record_input
+                .as_ref()
+                .map(|_| InputRecorder::default()),
+            running: Duration::ZERO,
+            owed: 0.0,
+            rate: 0,
+            rate_start: (Instant::now(), cycles),
+        }
+    }
+
+    /// Runs frames until told to quit or the program leaves its ROM
+    pub fn run(
+        mut self,
+        commands: Receiver<Command>,
+        published: Arc<Mutex<Published>>,
+    ) -> (Machine, Option<InputRecorder>) {
+        let frame_time = Duration::from_secs(1) / self.fps;
+        loop {
+            let start = Instant::now();
+            loop {
+                match commands.try_recv() {
+                    Ok(Command::Quit) | Err(TryRecvError::Disconnected) => {
+                        return (self.machine, self.recorder)
+                    }
+                    Ok(command) => self.handle(command),
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+
+            let running = self.run_frame(start + frame_time);
+            self.publish(&published);
+            if !running {
+                return (self.machine, self.recorder);
+            }
+            std::thread::sleep((start + frame_time).saturating_duration_since(Instant::now()));
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Key(key) => {
+                let scripted = self.player.as_ref().is_some_and(|p| !p.is_done());
+                if let Some(keyboard) = self.machine.device_mut::<Keyboard>().filter(|_| !scripted)
+                {
+                    keyboard.set_key(key);
+                }
+            }
+            Command::Hotkey(hotkey) => self.hotkey(hotkey),
+            Command::Inspect(range) => self.inspect = range,
+            Command::Quit => (),
+        }
+    }
+
+    fn hotkey(&mut self, hotkey: Hotkey) {
+        let path = &self.snapshot_path;
+        match hotkey {
+            Hotkey::SaveSnapshot => match self.machine.save_snapshot_file(path) {
+                Ok(()) => eprintln!("Saved snapshot to {}", path.display()),
+                Err(e) => eprintln!("Couldn't save snapshot to {}: {e}", path.display()),
+            },
+            Hotkey::LoadSnapshot => match self.machine.load_snapshot_file(path) {
+                Ok(()) => {
+                    eprintln!("Loaded snapshot from {}", path.display());
+                    self.halted = false;
+                    self.redraw = true;
+                }
+                Err(e) => eprintln!("Couldn't load snapshot from {}: {e}", path.display()),
+            },
+            Hotkey::Pause => self.paused = !self.paused,
+            Hotkey::Step if self.paused && !self.halted => self.run_instructions(1),
+            Hotkey::Step => (),
+            Hotkey::Faster => {
+                self.clock = match self.clock {
+                    Clock::PerSecond(n) if n < MAX_CLOCK => Clock::PerSecond(n * 2),
+                    _ => Clock::Unlimited,
+                }
+            }
+            Hotkey::Slower => {
+                let current = match self.clock {
+                    Clock::PerSecond(n) => n,
+                    Clock::Unlimited => self.rate.max(2),
+                };
+                self.clock = Clock::PerSecond((current / 2).max(1));
+            }
+        }
+    }
+
+    /// Runs a frame's worth of instructions: a clock's share of a second, or as many as fit
+    /// before `deadline` when it's unlimited. Returns false once the program has left its
+    /// ROM.
+    fn run_frame(&mut self, deadline: Instant) -> bool {
+        if !self.paused {
+            self.running += Duration::from_secs(1) / self.fps;
+            match self.clock {
+                Clock::PerSecond(n) => {
+                    self.owed += n as f64 / self.fps as f64;
+                    let budget = self.owed as u64;
+                    self.owed -= budget as f64;
+                    self.run_instructions(budget);
+                }
+                Clock::Unlimited => {
+                    while !(self.halted || self.finished || Instant::now() >= deadline) {
+                        self.run_instructions(UNLIMITED_CHUNK);
+                    }
+                }
+            }
+        }
+
+        let (start, start_cycles) = self.rate_start;
+        if start.elapsed() >= RATE_PERIOD {
+            let run = self.machine.cycles().saturating_sub(start_cycles);
+            self.rate = (run as f64 / start.elapsed().as_secs_f64()) as u64;
+            self.rate_start = (Instant::now(), self.machine.cycles());
+        }
+        !self.finished
+    }
+
+    /// Runs up to `budget` instructions, playing scripted input at the cycles it's due
+    fn run_instructions(&mut self, budget: u64) {
+        let end = self.machine.cycles() + budget;
+        let frame = (self.running.as_nanos() / INPUT_FRAME.as_nanos()) as u64;
+        while !self.halted && self.machine.cycles() < end {
+            if let Some(player) = &mut self.player {
+                player.apply(&mut self.machine, frame);
+            }
+            if let Some(recorder) = &mut self.recorder {
+                let key = self.machine.device::<Keyboard>().map(Keyboard::key);
+                recorder.record(self.machine.cycles(), key.unwrap_or_default());
+            }
+
+            let now = self.machine.cycles();
+            let next = self.player.as_ref().and_then(InputPlayer::next_cycle);
+            let limit = next.map_or(end, |next| next.min(end)) - now;
+            if self.machine.run_with_limit(limit) == StopReason::Halted {
+                // keep showing the final screen of a program that ends in a halting loop
+                self.halted = self.machine.is_halted();
+                self.finished = !self.halted;
+                return;
+            }
+        }
+    }
+
+    fn publish(&mut self, published: &Mutex<Published>) {
+        let mut dirty = match self.machine.device_mut::<Screen>() {
+            Some(screen) => screen.take_dirty(),
+            None => DirtyWords::default(),
+        };
+        if self.redraw {
+            dirty = DirtyWords::all();
+        }
+        let console_changed = self
+            .machine
+            .device_mut::<Console>()
+            .is_some_and(Console::take_dirty);
+
+        let mut published = published.lock().unwrap();
+        if !dirty.is_empty() {
+            let start = SCREEN_MEM_START as usize;
+            let words = self.machine.memory[start..start + SCREEN_WORDS].to_vec();
+            // the frontend hasn't drawn the last update yet, so it needs both sets of words
+            match &mut published.screen {
+                Some(update) => {
+                    update.words = words;
+                    update.dirty.merge(&dirty);
+                }
+                None => published.screen = Some(ScreenUpdate { words, dirty }),
+            }
+        }
+        if console_changed || self.redraw {
+            published.console = self.machine.device::<Console>().map(|c| c.text().into());
+        }
+        published.inspection = self.inspect.as_ref().map(|range| {
+            let end = (range.end as usize).min(MEMORY_SIZE);
+            let start = (range.start as usize).min(end);
+            Inspection {
+                a: self.machine.register_a(),
+                d: self.machine.register_d(),
+                pc: self.machine.pc(),
+                instruction: self.machine.rom().get(self.machine.pc() as usize).copied(),
+                ram_start: start as u16,
+                ram: self.machine.memory[start..end].to_vec(),
+            }
+        });
+        self.redraw = false;
+        published.status = Status {
+            cycles: self.machine.cycles(),
+            clock: self.clock,
+            rate: self.rate,
+            paused: self.paused,
+            halted: self.halted,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::compile_file, input::InputScript};
+
+    fn worker(clock: Clock) -> Worker {
+        let (instructions, _) = compile_file("resources/fill.asm", false).unwrap();
+        let mut machine = Machine::from_instructions(instructions);
+        machine.attach(Screen::default()).unwrap();
+        machine.attach(Keyboard::default()).unwrap();
+        let config = IoConfig {
+            clock,
+            fps: 50,
+            ..IoConfig::default()
+        };
+        Worker::new(machine, &config)
+    }
+
+    #[test]
+    fn clocks_set_instruction_budgets() {
+        // 75 instructions a second is 1.5 a frame, so the halves carry over
+        let mut worker = worker(Clock::PerSecond(75));
+        let deadline = Instant::now();
+        let cycles: Vec<u64> = (0..5)
+            .map(|_| {
+                assert!(worker.run_frame(deadline));
+                worker.machine.cycles()
+            })
+            .collect();
+
+        assert_eq!(cycles, [1, 3, 4, 6, 7]);
+    }
+
+    #[test]
+    fn pausing_stepping_and_speed() {
+        let mut worker = worker(Clock::PerSecond(1000));
+        let deadline = Instant::now();
+
+        worker.handle(Command::Hotkey(Hotkey::Pause));
+        worker.run_frame(deadline);
+        assert_eq!(worker.machine.cycles(), 0);
+        worker.handle(Command::Hotkey(Hotkey::Step));
+        worker.handle(Command::Hotkey(Hotkey::Step));
+        assert_eq!(worker.machine.cycles(), 2);
+
+        worker.handle(Command::Hotkey(Hotkey::Pause));
+        worker.handle(Command::Hotkey(Hotkey::Faster));
+        assert_eq!(worker.clock, Clock::PerSecond(2000));
+        worker.run_frame(deadline);
+        assert_eq!(worker.machine.cycles(), 42);
+
+        worker.handle(Command::Hotkey(Hotkey::Slower));
+        worker.handle(Command::Hotkey(Hotkey::Slower));
+        assert_eq!(worker.clock, Clock::PerSecond(500));
+        worker.clock = Clock::PerSecond(MAX_CLOCK);
+        worker.handle(Command::Hotkey(Hotkey::Faster));
+        assert_eq!(worker.clock, Clock::Unlimited);
+    }
+
+    #[test]
+    fn input_frames_count_running_time() {
+        let mut worker = worker(Clock::PerSecond(1000));
+        let script = InputScript::parse("1f key 65").unwrap();
+        worker.player = Some(InputPlayer::new(&script));
+        let key = |worker: &Worker| worker.machine.device::<Keyboard>().unwrap().key();
+        let deadline = Instant::now();
+
+        // time spent paused doesn't count
+        worker.handle(Command::Hotkey(Hotkey::Pause));
+        for _ in 0..10 {
+            worker.run_frame(deadline);
+        }
+        worker.handle(Command::Hotkey(Hotkey::Pause));
+        // at 50 fps, the first 100ms input frame lasts 5 window frames
+        for _ in 0..4 {
+            worker.run_frame(deadline);
+        }
+        assert_eq!(key(&worker), HackWord(0));
+        worker.run_frame(deadline);
+        assert_eq!(key(&worker), HackWord(65));
+    }
+
+    #[test]
+    fn keys_and_publishing() {
+        let mut worker = worker(Clock::PerSecond(5_000_000));
+        let published = Mutex::new(Published::default());
+
+        worker.handle(Command::Key(HackWord(65)));
+        for _ in 0..3 {
+            worker.run_frame(Instant::now());
+        }
+        worker.publish(&published);
+        let screen = published.lock().unwrap().screen.take().unwrap();
+        assert_eq!(screen.words.len(), SCREEN_WORDS);
+        // the key makes fill.asm blacken the screen
+        assert!(screen.words.contains(&HackWord(-1)));
+        // the first frame draws everything
+        assert_eq!(screen.dirty, DirtyWords::all());
+        assert_eq!(published.lock().unwrap().status.cycles, 300_000);
+
+        worker.publish(&published);
+        assert!(published.lock().unwrap().screen.is_none());
+
+        // later frames carry just the words written, gathered until the frontend takes them
+        let dirty_words = |published: &Mutex<Published>| {
+            let published = published.lock().unwrap();
+            published.screen.as_ref().unwrap().dirty.iter().count()
+        };
+        worker.run_instructions(1000);
+        worker.publish(&published);
+        let first = dirty_words(&published);
+        worker.run_instructions(1000);
+        worker.publish(&published);
+        let both = dirty_words(&published);
+        assert!(
+            first > 0 && both > first && both < SCREEN_WORDS,
+            "{first}, {both}"
+        );
+    }
+
+    #[test]
+    fn inspecting() {
+        let mut worker = worker(Clock::PerSecond(1000));
+        let published = Mutex::new(Published::default());
+
+        worker.handle(Command::Inspect(Some(16..20)));
+        worker.run_instructions(6);
+        worker.publish(&published);
+        let inspection = published.lock().unwrap().inspection.clone().unwrap();
+        assert_eq!(inspection.pc, 6);
+        assert_eq!(inspection.instruction, Some(worker.machine.rom()[6]));
+        assert_eq!((inspection.ram_start, inspection.ram.len()), (16, 4));
+        // fill.asm's first variable holds the end of the screen
+        assert_eq!(inspection.ram[0], HackWord(0x6000));
+
+        // ranges past the end of memory are cut short
+        worker.handle(Command::Inspect(Some(32760..u16::MAX)));
+        worker.publish(&published);
+        let inspection = published.lock().unwrap().inspection.clone().unwrap();
+        assert_eq!(inspection.ram.len(), 8);
+
+        worker.handle(Command::Inspect(None));
+        worker.publish(&published);
+        assert!(published.lock().unwrap().inspection.is_none());
+    }
+
+    #[test]
+    fn clock_parsing() {
+        assert_eq!("unlimited".parse(), Ok(Clock::Unlimited));
+        assert_eq!("500k".parse(), Ok(Clock::PerSecond(500_000)));
+        assert_eq!("2M".parse(), Ok(Clock::PerSecond(2_000_000)));
+        assert_eq!("60".parse(), Ok(Clock::PerSecond(60)));
+        assert!("0".parse::<Clock>().is_err());
+        assert!("fast".parse::<Clock>().is_err());
+        assert!("99999999999999999M".parse::<Clock>().is_err());
+        assert_eq!(Clock::PerSecond(1_500_000).to_string(), "1.5M/s");
+    }
+}
@@ -0,0 +1,273 @@
+//! Translating physical keys into the Hack character set.
+//!
+//! The default map is a US layout. A keymap file changes it a key at a time: each line is a
+//! key's name, as in [`Key`]'s variants, then its code and optionally its code with shift
+//! held, written as in input scripts:
+//!
+//! ```text
+//! # UK layout
+//! Key2 '2' '"'
+//! Apostrophe '\'' '@'
+//! ```
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use minifb::Key;
+
+use crate::{
+    common::{err, Res},
+    hackword::HackWord,
+    input::{parse_key, tokenize},
+};
+
+/// Each key's code without and with shift, on a US keyboard
+#[rustfmt::skip]
+const US_LAYOUT: [(Key, u8, u8); 49] = [
+    (Key::Key0, b'0', b')'), (Key::Key1, b'1', b'!'), (Key::Key2, b'2', b'@'),
+    (Key::Key3, b'3', b'#'), (Key::Key4, b'4', b'$'), (Key::Key5, b'5', b'%'),
+    (Key::Key6, b'6', b'^'), (Key::Key7, b'7', b'&'), (Key::Key8, b'8', b'*'),
+    (Key::Key9, b'9', b'('),
+    (Key::A, b'a', b'A'), (Key::B, b'b', b'B'), (Key::C, b'c', b'C'), (Key::D, b'd', b'D'),
+    (Key::E, b'e', b'E'), (Key::F, b'f', b'F'), (Key::G, b'g', b'G'), (Key::H, b'h', b'H'),
+    (Key::I, b'i', b'I'), (Key::J, b'j', b'J'), (Key::K, b'k', b'K'), (Key::L, b'l', b'L'),
+    (Key::M, b'm', b'M'), (Key::N, b'n', b'N'), (Key::O, b'o', b'O'), (Key::P, b'p', b'P'),
+    (Key::Q, b'q', b'Q'), (Key::R, b'r', b'R'), (Key::S, b's', b'S'), (Key::T, b't', b'T'),
+    (Key::U, b'u', b'U'), (Key::V, b'v', b'V'), (Key::W, b'w', b'W'), (Key::X, b'x', b'X'),
+    (Key::Y, b'y', b'Y'), (Key::Z, b'z', b'Z'),
+    (Key::Space, b' ', b' '), (Key::Tab, b'\t', b'\t'),
+    (Key::Backquote, b'`', b'~'), (Key::Minus, b'-', b'_'), (Key::Equal, b'=', b'+'),
+    (Key::LeftBracket, b'[', b'{'), (Key::RightBracket, b']', b'}'),
+    (Key::Backslash, b'\\', b'|'), (Key::Semicolon, b';', b':'),
+    (Key::Apostrophe, b'\'', b'"'), (Key::Comma, b',', b'<'), (Key::Period, b'.', b'>'),
+    (Key::Slash, b'/', b'?'),
+];
+
+/// Codes of keys that shift doesn't change: the nand2tetris codes for keys without a
+/// character, and the number pad
+#[rustfmt::skip]
+const SPECIAL_KEYS: [(Key, i16); 44] = [
+    (Key::Enter, 128), (Key::Backspace, 129), (Key::Left, 130), (Key::Up, 131),
+    (Key::Right, 132), (Key::Down, 133), (Key::Home, 134), (Key::End, 135),
+    (Key::PageUp, 136), (Key::PageDown, 137), (Key::Insert, 138), (Key::Delete, 139),
+    (Key::Escape, 140), (Key::F1, 141), (Key::F2, 142), (Key::F3, 143), (Key::F4, 144),
+    (Key::F5, 145), (Key::F6, 146), (Key::F7, 147), (Key::F8, 148), (Key::F9, 149),
+    (Key::F10, 150), (Key::F11, 151), (Key::F12, 152),
+    (Key::NumPad0, b'0' as i16), (Key::NumPad1, b'1' as i16), (Key::NumPad2, b'2' as i16),
+    (Key::NumPad3, b'3' as i16), (Key::NumPad4, b'4' as i16), (Key::NumPad5, b'5' as i16),
+    (Key::NumPad6, b'6' as i16), (Key::NumPad7, b'7' as i16), (Key::NumPad8, b'8' as i16),
+    (Key::NumPad9, b'9' as i16), (Key::NumPadDot, b'.' as i16),
+    (Key::NumPadSlash, b'/' as i16), (Key::NumPadAsterisk, b'*' as i16),
+    (Key::NumPadMinus, b'-' as i16), (Key::NumPadPlus, b'+' as i16),
+    (Key::NumPadEnter, 128),
+    // modifiers on their own don't type anything
+    (Key::LeftShift, 0), (Key::RightShift, 0), (Key::CapsLock, 0),
+];
+
+/// Every key minifb knows, for looking keys up by name
+#[rustfmt::skip]
+const ALL_KEYS: [Key; 106] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7,
+    Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K,
+    Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V,
+    Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9,
+    Key::F10, Key::F11, Key::F12, Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up, Key::Apostrophe, Key::Backquote,
+    Key::Backslash, Key::Comma, Key::Equal, Key::LeftBracket, Key::Minus, Key::Period,
+    Key::RightBracket, Key::Semicolon, Key::Slash, Key::Backspace, Key::Delete, Key::End,
+    Key::Enter, Key::Escape, Key::Home, Key::Insert, Key::Menu, Key::PageDown, Key::PageUp,
+    Key::Pause, Key::Space, Key::Tab, Key::NumLock, Key::CapsLock, Key::ScrollLock,
+    Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4, Key::NumPad5,
+    Key::NumPad6, Key::NumPad7, Key::NumPad8, Key::NumPad9, Key::NumPadDot,
+    Key::NumPadSlash, Key::NumPadAsterisk, Key::NumPadMinus, Key::NumPadPlus,
+    Key::NumPadEnter,
+    Key::LeftAlt, Key::RightAlt, Key::LeftSuper, Key::RightSuper,
+];
+
+/// Maps keys to the codes the keyboard register shows, following the most recently pressed
+/// key while several are held. Caps Lock swaps shift for keys that type a letter.
+#[derive(Clone, Debug)]
+pub struct KeyMap {
+    codes: HashMap<Key, (HackWord, HackWord)>,
+    current: Option<Key>,
+    caps_lock: bool,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        let mut codes = HashMap::new();
+        for (key, plain, shifted) in US_LAYOUT {
+            codes.insert(key, (HackWord(plain.into()), HackWord(shifted.into())));
+        }
+        for (key, code) in SPECIAL_KEYS {
+            codes.insert(key, (HackWord(code), HackWord(code)));
+        }
+        Self {
+            codes,
+            current: None,
+            caps_lock: false,
+        }
+    }
+}
+
+impl KeyMap {
+    /// The US layout, changed by the lines of a keymap file
+    pub fn parse(text: &str) -> Res<Self> {
+        let mut map = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            map.parse_line(line)
+                .map_err(|e| format!("Line {}: {e}", i + 1))?;
+        }
+        Ok(map)
+    }
+
+    pub fn read_file(path: impl AsRef<Path>) -> Res<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Res {
+        let tokens = tokenize(line)?;
+        let (name, plain, shifted) = match tokens.as_slice() {
+            [] => return Ok(()),
+            [name, plain] => (name, parse_key(plain)?, None),
+            [name, plain, shifted] => (name, parse_key(plain)?, Some(parse_key(shifted)?)),
+            _ => {
+                return Err(err(
+                    "Expected a key name, a code and optionally a shifted code",
+                ))
+            }
+        };
+        let key = *ALL_KEYS
+            .iter()
+            .find(|key| format!("{key:?}") == *name)
+            .ok_or_else(|| format!("Unknown key '{name}'"))?;
+        self.codes.insert(key, (plain, shifted.unwrap_or(plain)));
+        Ok(())
+    }
+
+    /// The code of `key`, if it types anything
+    pub fn code(&self, key: Key, shift: bool) -> Option<HackWord> {
+        let &(plain, shifted) = self.codes.get(&key)?;
+        let code = if shift { shifted } else { plain };
+        (code != HackWord(0)).then_some(code)
+    }
+
+    /// The code of `key` with Caps Lock applied
+    fn typed(&self, key: Key, shift: bool) -> Option<HackWord> {
+        let letter = self
+            .code(key, false)
+            .is_some_and(|code| u8::try_from(code.0).is_ok_and(|c| c.is_ascii_lowercase()));
+        self.code(key, shift ^ (self.caps_lock && letter))
+    }
+
+    /// The keyboard register's value given the keys held down and those newly pressed this
+    /// frame. A new key takes over from one already held, which keeps going once the new
+    /// one is released.
+    pub fn update(&mut self, held: &[Key], pressed: &[Key]) -> HackWord {
+        if pressed.contains(&Key::CapsLock) {
+            self.caps_lock = !self.caps_lock;
+        }
+        let shift = held
+            .iter()
+            .any(|key| matches!(key, Key::LeftShift | Key::RightShift));
+        let types = |key: &&Key| self.typed(**key, shift).is_some();
+
+        self.current = pressed
+            .iter()
+            .rev()
+            .find(types)
+            .or_else(|| self.current.as_ref().filter(|key| held.contains(key)))
+            .or_else(|| held.iter().find(types))
+            .copied();
+        self.current
+            .and_then(|key| self.typed(key, shift))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shift_and_symbols() {
+        let mut map = KeyMap::default();
+
+        assert_eq!(map.update(&[Key::A], &[Key::A]), HackWord(b'a'.into()));
+        assert_eq!(
+            map.update(&[Key::LeftShift, Key::A], &[Key::LeftShift]),
+            HackWord(b'A'.into())
+        );
+        assert_eq!(map.code(Key::Key1, true), Some(HackWord(b'!'.into())));
+        assert_eq!(map.code(Key::Slash, true), Some(HackWord(b'?'.into())));
+        assert_eq!(map.code(Key::Backquote, false), Some(HackWord(b'`'.into())));
+        assert_eq!(map.code(Key::Apostrophe, true), Some(HackWord(b'"'.into())));
+        assert_eq!(map.code(Key::Enter, true), Some(HackWord(128)));
+        assert_eq!(map.code(Key::LeftShift, false), None);
+        assert_eq!(map.update(&[Key::LeftShift], &[]), HackWord(0));
+    }
+
+    #[test]
+    fn caps_lock_swaps_shift_for_letters() {
+        let mut map = KeyMap::default();
+
+        assert_eq!(map.update(&[Key::CapsLock], &[Key::CapsLock]), HackWord(0));
+        assert_eq!(map.update(&[Key::A], &[Key::A]), HackWord(b'A'.into()));
+        assert_eq!(
+            map.update(&[Key::LeftShift, Key::A], &[Key::LeftShift]),
+            HackWord(b'a'.into())
+        );
+        assert_eq!(
+            map.update(&[Key::Key1], &[Key::Key1]),
+            HackWord(b'1'.into())
+        );
+        assert_eq!(
+            map.update(&[Key::LeftShift, Key::Key1], &[Key::LeftShift]),
+            HackWord(b'!'.into())
+        );
+
+        // holding it down doesn't toggle it again, pressing it again does
+        map.update(&[Key::CapsLock], &[]);
+        map.update(&[Key::CapsLock], &[Key::CapsLock]);
+        assert_eq!(map.update(&[Key::A], &[Key::A]), HackWord(b'a'.into()));
+    }
+
+    #[test]
+    fn the_newest_key_wins() {
+        let mut map = KeyMap::default();
+
+        assert_eq!(map.update(&[Key::Z], &[Key::Z]), HackWord(b'z'.into()));
+        // A is pressed while Z is held, then released while Z still is
+        assert_eq!(
+            map.update(&[Key::A, Key::Z], &[Key::A]),
+            HackWord(b'a'.into())
+        );
+        assert_eq!(map.update(&[Key::A, Key::Z], &[]), HackWord(b'a'.into()));
+        assert_eq!(map.update(&[Key::Z], &[]), HackWord(b'z'.into()));
+        assert_eq!(map.update(&[], &[]), HackWord(0));
+    }
+
+    #[test]
+    fn keymap_files() {
+        let map = KeyMap::read_file("resources/keymaps/uk.keymap").unwrap();
+
+        assert_eq!(map.code(Key::Key2, true), Some(HackWord(b'"'.into())));
+        assert_eq!(map.code(Key::Apostrophe, true), Some(HackWord(b'@'.into())));
+        assert_eq!(map.code(Key::Backslash, false), Some(HackWord(b'#'.into())));
+        // keys the file leaves alone keep the US layout
+        assert_eq!(map.code(Key::Key1, true), Some(HackWord(b'!'.into())));
+
+        // keys the US layout doesn't use can be mapped too
+        let map = KeyMap::parse("F13 'x'\nNumLock 1").unwrap();
+        assert_eq!(map.code(Key::F13, false), Some(HackWord(b'x'.into())));
+        assert_eq!(map.code(Key::NumLock, true), Some(HackWord(1)));
+
+        assert!(KeyMap::parse("Nothing 1").is_err());
+        assert!(KeyMap::parse("A 1 2 3").is_err());
+        let error = KeyMap::parse("A 'a'\nB").unwrap_err();
+        assert!(error.to_string().starts_with("Line 2:"), "{error}");
+    }
+}
@@ -1,15 +1,126 @@
-use crate::common::*;
+use std::collections::BTreeSet;
+
 use crate::hackword::*;
 use crate::instruction::*;
 
+mod breakpoints;
+mod device;
+mod history;
+mod snapshot;
+mod threaded;
+mod tracer;
+
+pub use breakpoints::*;
+pub use device::*;
+pub use tracer::*;
+
 pub const MEMORY_SIZE: usize = 32768;
+pub const ROM_SIZE: usize = 32768;
+
+/// A ROM slot, decoded once when the program is loaded
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+enum Op {
+    LoadA(HackWord),
+    Compute {
+        comp: Comp,
+        should_deref: bool,
+        dest: Dest,
+        jump: Jump,
+    },
+    /// Nothing executable is loaded here: stepping onto it halts the machine
+    Trap,
+    /// `@n` at address n followed by an unconditional jump that writes nothing, the usual
+    /// way to end a program. It runs like `@n`, but once the loop has set A to n another
+    /// pass would change nothing, so reaching it again halts the machine.
+    Halt(HackWord),
+}
+
+impl From<Instruction> for Op {
+    fn from(instruction: Instruction) -> Self {
+        match instruction {
+            Instruction::A(x) => Op::LoadA(HackWord(x as i16)),
+            Instruction::C {
+                comp,
+                should_deref,
+                dest,
+                jump,
+            } => Op::Compute {
+                comp,
+                should_deref,
+                dest,
+                jump,
+            },
+        }
+    }
+}
+
+// the address bus is 15 bits wide, so A values past the end of memory wrap around
+fn bus_address(a: HackWord) -> u16 {
+    (a.to_usize() % MEMORY_SIZE) as u16
+}
+
+/// The memory touched by a single instruction
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct Access {
+    /// M, if the instruction read it
+    pub read: Option<MemoryRead>,
+    pub write: Option<MemoryWrite>,
+    /// Whether the instruction jumped
+    pub jumped: bool,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct MemoryRead {
+    pub address: u16,
+    /// The value loaded, which for a device's address is whatever the device returned
+    pub value: HackWord,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct MemoryWrite {
+    pub address: u16,
+    pub old: HackWord,
+    pub new: HackWord,
+}
+
+/// How a C-instruction updates state when it writes to several destinations at once
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum Semantics {
+    /// As the Hack CPU does: A, D, M and the PC all latch on the clock edge, so M writes
+    /// and jumps use the value A held before the instruction ran
+    #[default]
+    Conformant,
+    /// A is written first, so M writes and jumps see the freshly computed A
+    Legacy,
+}
 
 pub struct Machine {
+    /// The words as loaded, which the decoded `program` can't give back: it fills the whole
+    /// ROM, and decoding drops the unused bits of C-instructions
     instructions: Vec<HackWord>,
+    program: Box<[Op]>,
+    blocks: Option<threaded::BlockCache>,
     current_instruction: HackWord,
+    /// RAM, including the words stored for memory-mapped devices. Reading and writing it
+    /// directly bypasses devices; [`Machine::peek`] and [`Machine::poke`] don't.
     pub memory: [HackWord; MEMORY_SIZE],
     register_a: HackWord,
     register_d: HackWord,
+    semantics: Semantics,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+    cycles: u64,
+    history: Option<history::History>,
+    tracer: Option<Box<dyn Tracer + Send>>,
+    devices: Vec<Box<dyn Device>>,
+    /// The lowest address any device claims
+    mmio_floor: u16,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Machine {
@@ -22,43 +133,121 @@ impl Machine {
     pub fn new() -> Self {
         Self {
             instructions: Vec::new(),
+            program: vec![Op::Trap; ROM_SIZE].into_boxed_slice(),
+            blocks: None,
             current_instruction: HackWord::default(),
             memory: [HackWord::default(); MEMORY_SIZE],
             register_a: HackWord::default(),
             register_d: HackWord::default(),
+            semantics: Semantics::default(),
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            cycles: 0,
+            history: None,
+            tracer: None,
+            devices: Vec::new(),
+            mmio_floor: MEMORY_SIZE as u16,
         }
     }
 
+    pub fn pc(&self) -> u16 {
+        self.current_instruction.as_u16()
+    }
+
+    /// How many instructions have run since the machine was created
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn register_a(&self) -> HackWord {
+        self.register_a
+    }
+
+    pub fn register_d(&self) -> HackWord {
+        self.register_d
+    }
+
+    /// Sets A from outside the program, as test scripts do. Undo history can't reverse
+    /// this, so it's cleared, as it is by the other setters.
+    pub fn set_register_a(&mut self, value: HackWord) {
+        self.clear_history();
+        self.register_a = value;
+    }
+
+    pub fn set_register_d(&mut self, value: HackWord) {
+        self.clear_history();
+        self.register_d = value;
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.clear_history();
+        self.set_instruction(HackWord::from_u16(pc));
+    }
+
+    /// The program loaded into ROM
+    pub fn rom(&self) -> &[HackWord] {
+        &self.instructions
+    }
+
+    pub fn semantics(&self) -> Semantics {
+        self.semantics
+    }
+
+    pub fn set_semantics(&mut self, semantics: Semantics) {
+        self.semantics = semantics;
+    }
+
+    /// Sets the PC, which like the hardware's is 15 bits wide, so jumps to negative
+    /// addresses and running off the end of ROM wrap round
     fn set_instruction(&mut self, instruction: HackWord) {
-        self.current_instruction = instruction;
+        self.current_instruction = HackWord::from_u16(instruction.as_u16() % ROM_SIZE as u16);
     }
 
-    fn m(&self) -> HackWord {
-        self.memory[self.register_a.to_usize()]
+    #[inline(always)]
+    fn m(&mut self) -> HackWord {
+        self.load(bus_address(self.register_a))
     }
 
-    fn m_mut(&mut self) -> &mut HackWord {
-        &mut self.memory[self.register_a.to_usize()]
+    /// Executes one instruction, returning `false` without changing any state if the PC
+    /// points outside the loaded program
+    #[inline]
+    pub fn step(&mut self) -> bool {
+        self.advance().is_some()
     }
 
-    fn ins(&self) -> Res<Option<Instruction>> {
-        Ok({
-            let i = self.current_instruction.to_usize();
-            if i < self.instructions.len() {
-                Some(self.instructions[i].try_into()?)
-            } else {
-                None
-            }
-        })
+    /// Executes one instruction, counting it and passing it on to the undo log and tracer
+    #[inline(always)]
+    fn advance(&mut self) -> Option<Access> {
+        let before = self
+            .is_observed()
+            .then_some((self.pc(), self.register_a, self.register_d));
+        let access = self.execute()?;
+        self.cycles += 1;
+        if let Some((pc, a, d)) = before {
+            self.record(pc, a, d, access.write);
+            self.emit_trace(pc, a, d, access);
+        }
+        Some(access)
+    }
+
+    /// Whether anything needs to see each instruction as it runs
+    #[inline(always)]
+    fn is_observed(&self) -> bool {
+        self.history.is_some() || self.tracer.is_some()
     }
 
-    fn step_ins(&mut self, current: Instruction) {
-        match current {
-            Instruction::A(dest) => {
-                self.register_a = HackWord(dest as i16);
+    /// Executes one instruction and reports the memory it touched, or returns `None` if the
+    /// PC points outside the loaded program. Callers that ignore the report pay nothing for it.
+    #[inline(always)]
+    fn execute(&mut self) -> Option<Access> {
+        let mut access = Access::default();
+        match self.program[self.current_instruction.to_usize() % ROM_SIZE] {
+            Op::Halt(value) if self.register_a == value => return None,
+            Op::LoadA(value) | Op::Halt(value) => {
+                self.register_a = value;
                 self.set_instruction(self.current_instruction + HackWord::one());
             }
-            Instruction::C {
+            Op::Compute {
                 should_deref,
                 comp,
                 dest,
@@ -67,30 +256,23 @@ impl Machine {
                 let value = {
                     let d = self.register_d;
                     let a = if should_deref {
-                        self.m()
+                        let value = self.m();
+                        access.read = Some(MemoryRead {
+                            address: bus_address(self.register_a),
+                            value,
+                        });
+                        value
                     } else {
                         self.register_a
                     };
-                    match comp {
-                        Comp::Zero => HackWord::zero(),
-                        Comp::One => HackWord::one(),
-                        Comp::MinusOne => HackWord::minus_one(),
-                        Comp::D => d,
-                        Comp::A => a,
-                        Comp::NotD => !d,
-                        Comp::NotA => !a,
-                        Comp::MinusD => -d,
-                        Comp::MinusA => -a,
-                        Comp::DPlus1 => d + HackWord::one(),
-                        Comp::APlus1 => a + HackWord::one(),
-                        Comp::DMinus1 => d - HackWord::one(),
-                        Comp::AMinus1 => a - HackWord::one(),
-                        Comp::DPlusA => d + a,
-                        Comp::DMinusA => d - a,
-                        Comp::AMinusD => a - d,
-                        Comp::DAndA => d & a,
-                        Comp::DOrA => d | a,
-                    }
+                    comp.compute(d, a)
+                };
+
+                // in conformant mode, M and the jump target are addressed by the pre-instruction A
+                let address = match self.semantics {
+                    Semantics::Conformant => self.register_a,
+                    Semantics::Legacy if dest.a => value,
+                    Semantics::Legacy => self.register_a,
                 };
 
                 if dest.a {
@@ -100,33 +282,277 @@ impl Machine {
                     self.register_d = value;
                 }
                 if dest.m {
-                    *self.m_mut() = value;
+                    let address = bus_address(address);
+                    let (old, new) = self.store(address, value);
+                    access.write = Some(MemoryWrite { address, old, new });
                 }
 
-                self.set_instruction(if jump.should_jump(value) {
-                    self.register_a
+                access.jumped = jump.should_jump(value);
+                self.set_instruction(if access.jumped {
+                    address
                 } else {
                     self.current_instruction + HackWord::one()
                 })
             }
+            Op::Trap => return None,
         }
+        Some(access)
     }
 
-    pub fn step(&mut self) -> Res<bool> {
-        Ok(if let Some(current) = self.ins()? {
-            self.step_ins(current);
-            true
-        } else {
-            false
-        })
+    /// Whether the PC is back at a `@n`/`0;JMP` loop that has already run, so that the
+    /// machine treats it as halting
+    pub fn is_halted(&self) -> bool {
+        matches!(
+            self.program[self.current_instruction.to_usize() % ROM_SIZE],
+            Op::Halt(target) if target == self.register_a
+        )
+    }
+
+    /// Executes one instruction as the hardware would, even where [`Machine::step`] stops:
+    /// a halting loop runs its `@n` again, and the empty ROM past the program holds `@0`
+    pub fn tick(&mut self) {
+        if self.step() {
+            return;
+        }
+        let (pc, a, d) = (self.pc(), self.register_a, self.register_d);
+        self.register_a = match self.program[pc as usize % ROM_SIZE] {
+            Op::Halt(value) => value,
+            _ => HackWord::zero(),
+        };
+        self.set_instruction(self.current_instruction + HackWord::one());
+        self.cycles += 1;
+        if self.is_observed() {
+            self.record(pc, a, d, None);
+            self.emit_trace(pc, a, d, Access::default());
+        }
     }
 
-    pub fn run(&mut self) -> Res {
-        while self.step()? {}
-        Ok(())
+    /// Runs for at most `max_cycles` instructions, returning [`StopReason::Halted`] if the
+    /// program halted first and [`StopReason::StepLimit`] otherwise
+    pub fn run_with_limit(&mut self, max_cycles: u64) -> StopReason {
+        for _ in 0..max_cycles {
+            if !self.step() {
+                return StopReason::Halted;
+            }
+        }
+        StopReason::StepLimit
     }
 
+    /// Runs until the PC leaves the program or reaches a halting loop. With the `threaded`
+    /// feature this uses the basic-block engine.
+    pub fn run(&mut self) {
+        if cfg!(feature = "threaded") {
+            self.run_threaded();
+        } else {
+            while self.step() {}
+        }
+    }
+
+    /// Loads a program into ROM, decoding each word up front. Words beyond the 32K ROM
+    /// are ignored.
     pub fn load_instructions(&mut self, instructions: Vec<HackWord>) {
+        self.program.fill(Op::Trap);
+        self.blocks = None;
+        self.clear_history();
+        for (op, &word) in self.program.iter_mut().zip(&instructions) {
+            *op = Instruction::from(word).into();
+        }
+        for pc in 0..instructions.len().min(ROM_SIZE).saturating_sub(1) {
+            if let (Op::LoadA(target), Op::Compute { dest, jump, .. }) =
+                (self.program[pc], self.program[pc + 1])
+            {
+                if target.to_usize() == pc && jump == Jump::JMP && dest == Dest::default() {
+                    self.program[pc] = Op::Halt(target);
+                }
+            }
+        }
         self.instructions = instructions;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::compile_lines;
+
+    const A: i16 = 100;
+    const D: i16 = 7;
+
+    /// The dest field of the nand2tetris CPU specification, as `d1 d2 d3` (A, D, M)
+    const DESTS: [(&str, [bool; 3]); 8] = [
+        ("null", [false, false, false]),
+        ("M", [false, false, true]),
+        ("D", [false, true, false]),
+        ("MD", [false, true, true]),
+        ("A", [true, false, false]),
+        ("AM", [true, false, true]),
+        ("AD", [true, true, false]),
+        ("AMD", [true, true, true]),
+    ];
+
+    /// The jump field of the nand2tetris CPU specification: whether the jump is taken
+    /// when `out < 0`, `out = 0` and `out > 0`
+    const JUMPS: [(Jump, [bool; 3]); 8] = [
+        (Jump::Null, [false, false, false]),
+        (Jump::JGT, [false, false, true]),
+        (Jump::JEQ, [false, true, false]),
+        (Jump::JGE, [false, true, true]),
+        (Jump::JLT, [true, false, false]),
+        (Jump::JNE, [true, false, true]),
+        (Jump::JLE, [true, true, false]),
+        (Jump::JMP, [true, true, true]),
+    ];
+
+    /// Runs a single `dest=M+1;jump` with A=100, D=7 and RAM[100]=m
+    fn step_m_plus_1(semantics: Semantics, dest: Dest, jump: Jump, m: i16) -> Machine {
+        let mut machine = Machine::from_instructions(vec![Instruction::C {
+            comp: Comp::APlus1,
+            should_deref: true,
+            dest,
+            jump,
+        }
+        .into()]);
+        machine.set_semantics(semantics);
+        machine.register_a = HackWord(A);
+        machine.register_d = HackWord(D);
+        machine.memory[A as usize] = HackWord(m);
+
+        assert!(machine.step());
+        machine
+    }
+
+    #[test]
+    fn cpu_truth_table() {
+        for (name, [a, d, m]) in DESTS {
+            let dest = Dest { a, d, m };
+            for (jump, taken) in JUMPS {
+                // RAM[A] values giving out < 0, out = 0 and out > 0
+                for (m_in, taken) in [-6, -1, 41].into_iter().zip(taken) {
+                    let out = m_in + 1;
+                    let machine = step_m_plus_1(Semantics::Conformant, dest, jump, m_in);
+                    let case = format!("{name}=M+1;{jump:?} with M={m_in}");
+
+                    let expected_a = if a { out } else { A };
+                    let expected_d = if d { out } else { D };
+                    let expected_m = if m { out } else { m_in };
+                    let expected_pc = if taken { A } else { 1 };
+
+                    assert_eq!(machine.register_a, HackWord(expected_a), "A after {case}");
+                    assert_eq!(machine.register_d, HackWord(expected_d), "D after {case}");
+                    assert_eq!(
+                        machine.memory[A as usize],
+                        HackWord(expected_m),
+                        "RAM[{A}] after {case}"
+                    );
+                    assert_eq!(
+                        machine.current_instruction,
+                        HackWord(expected_pc),
+                        "PC after {case}"
+                    );
+                    if out != A && out >= 0 {
+                        assert_eq!(
+                            machine.memory[out as usize],
+                            HackWord::zero(),
+                            "RAM[{out}] after {case}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_semantics_use_updated_a() {
+        let dest = Dest {
+            a: true,
+            d: false,
+            m: true,
+        };
+        let machine = step_m_plus_1(Semantics::Legacy, dest, Jump::JMP, 41);
+
+        assert_eq!(machine.register_a, HackWord(42));
+        assert_eq!(machine.memory[A as usize], HackWord(41));
+        assert_eq!(machine.memory[42], HackWord(42));
+        assert_eq!(machine.current_instruction, HackWord(42));
+    }
+
+    #[test]
+    fn pointer_increment_writes_through_old_pointer() {
+        let mut machine = Machine::new();
+        machine.memory[0] = HackWord(256);
+
+        machine.load_instructions(compile_lines("@0\nAM=M+1\nD=A\n@1\nM=D").unwrap());
+        machine.run();
+
+        assert_eq!(machine.memory[0], HackWord(257));
+        assert_eq!(machine.memory[257], HackWord::zero());
+        assert_eq!(machine.memory[1], HackWord(257));
+    }
+
+    #[test]
+    fn arithmetic_overflow_wraps() {
+        let mut machine = Machine::new();
+        machine.load_instructions(compile_lines("@32767\nD=A+1\n@0\nM=D\nM=-M").unwrap());
+        machine.run();
+
+        assert_eq!(machine.memory[0], HackWord(i16::MIN));
+    }
+
+    #[test]
+    fn end_loops_halt() {
+        let mut machine = Machine::from_instructions(
+            compile_lines("@7\nD=A\n@0\nM=D\n(END)\n@END\n0;JMP").unwrap(),
+        );
+        machine.run();
+
+        // the loop runs once, as on hardware, leaving A at END
+        assert_eq!(machine.memory[0], HackWord(7));
+        assert_eq!(machine.pc(), 4);
+        assert_eq!(machine.register_a(), HackWord(4));
+        assert!(machine.is_halted());
+        assert!(!machine.step());
+        assert_eq!(machine.cycles(), 6);
+    }
+
+    #[test]
+    fn loops_that_change_state_do_not_halt() {
+        for asm in ["(L)\n@L\nD=D+1;JMP", "(L)\n@L\nM=M+1;JMP", "(L)\n@1\n0;JMP"] {
+            let mut machine = Machine::from_instructions(compile_lines(asm).unwrap());
+            assert!(!machine.is_halted(), "{asm}");
+            assert_eq!(machine.run_with_limit(100), StopReason::StepLimit, "{asm}");
+        }
+
+        let mut machine = Machine::from_instructions(compile_lines("@3\n0;JMP").unwrap());
+        assert_eq!(machine.run_with_limit(100), StopReason::Halted);
+        assert_eq!(machine.cycles(), 2);
+    }
+
+    #[test]
+    fn stepping_outside_the_program_traps() {
+        let mut machine = Machine::from_instructions(compile_lines("@5\n0;JMP").unwrap());
+
+        assert!(machine.step());
+        assert!(machine.step());
+        assert_eq!(machine.current_instruction, HackWord(5));
+        assert!(!machine.step());
+        assert_eq!(machine.current_instruction, HackWord(5));
+    }
+
+    #[test]
+    fn ticks_keep_running_where_steps_stop() {
+        let mut machine = Machine::from_instructions(compile_lines("(END)\n@END\n0;JMP").unwrap());
+        for _ in 0..5 {
+            machine.tick();
+        }
+        assert_eq!((machine.pc(), machine.register_a()), (1, HackWord(0)));
+        assert_eq!(machine.cycles(), 5);
+
+        let mut machine = Machine::from_instructions(compile_lines("@5\nD=A").unwrap());
+        machine.register_d = HackWord(1);
+        for _ in 0..4 {
+            machine.tick();
+        }
+        assert_eq!((machine.pc(), machine.register_a()), (4, HackWord(0)));
+        assert_eq!(machine.register_d, HackWord(5));
+    }
+}
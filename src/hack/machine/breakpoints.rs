@@ -0,0 +1,286 @@
+use std::ops::RangeInclusive;
+
+use super::{Access, Machine};
+use crate::asm::AsmDebug;
+use crate::common::Res;
+use crate::hackword::HackWord;
+
+/// Which kinds of memory access a watchpoint stops on
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// Stops execution when an instruction touches any address in `range`
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn address(address: u16, kind: WatchKind) -> Self {
+        Self {
+            range: address..=address,
+            kind,
+        }
+    }
+
+    pub fn range(range: RangeInclusive<u16>, kind: WatchKind) -> Self {
+        Self { range, kind }
+    }
+}
+
+/// Why [`Machine::run_until_break`] returned
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum StopReason {
+    /// The PC reached a breakpoint; the instruction there has not run yet
+    Breakpoint(u16),
+    /// The last instruction touched a watched address. For reads, `old` and `new` are both
+    /// the value read.
+    Watchpoint {
+        address: u16,
+        kind: WatchKind,
+        old: HackWord,
+        new: HackWord,
+    },
+    /// The PC left the program or reached a halting `@n`/`0;JMP` loop
+    Halted,
+    /// The step limit ran out
+    StepLimit,
+    /// [`Machine::reverse_continue`] undid every recorded step
+    HistoryStart,
+}
+
+impl Machine {
+    pub fn add_breakpoint(&mut self, pc: u16) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Returns whether there was a breakpoint at `pc`
+    pub fn remove_breakpoint(&mut self, pc: u16) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Adds a breakpoint at the instruction following a label, returning its ROM address
+    pub fn add_label_breakpoint(&mut self, debug: &AsmDebug, label: &str) -> Res<u16> {
+        let pc = debug
+            .label_address(label)
+            .ok_or_else(|| format!("Unknown label '{label}'"))?;
+        self.add_breakpoint(pc);
+        Ok(pc)
+    }
+
+    /// Adds a breakpoint at the first instruction on or after a (1-based) source line,
+    /// returning its ROM address
+    pub fn add_line_breakpoint(&mut self, debug: &AsmDebug, line: usize) -> Res<u16> {
+        let pc = debug
+            .line_address(line)
+            .ok_or_else(|| format!("No instructions on or after line {line}"))?;
+        self.add_breakpoint(pc);
+        Ok(pc)
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Returns whether the watchpoint was set
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != before
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Runs for at most `max_steps` instructions, stopping early at a breakpoint, a watched
+    /// memory access or when the program halts. A breakpoint at the starting PC is ignored,
+    /// so calling this again after a breakpoint continues past it.
+    pub fn run_until_break(&mut self, max_steps: u64) -> StopReason {
+        for step in 0..max_steps {
+            let pc = self.current_instruction.as_u16();
+            if step > 0 && self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint(pc);
+            }
+
+            let Some(access) = self.advance() else {
+                return StopReason::Halted;
+            };
+            if let Some(reason) = self.watched(access) {
+                return reason;
+            }
+        }
+        StopReason::StepLimit
+    }
+
+    fn watched(&self, access: Access) -> Option<StopReason> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+
+        let hit = |address: u16, kind: WatchKind| {
+            self.watchpoints.iter().any(|w| {
+                w.range.contains(&address) && (w.kind == kind || w.kind == WatchKind::ReadWrite)
+            })
+        };
+
+        if let Some(write) = access.write {
+            if hit(write.address, WatchKind::Write) {
+                return Some(StopReason::Watchpoint {
+                    address: write.address,
+                    kind: WatchKind::Write,
+                    old: write.old,
+                    new: write.new,
+                });
+            }
+        }
+        if let Some(read) = access.read {
+            if hit(read.address, WatchKind::Read) {
+                return Some(StopReason::Watchpoint {
+                    address: read.address,
+                    kind: WatchKind::Read,
+                    old: read.value,
+                    new: read.value,
+                });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{compile, compile_file, compile_lines};
+    use crate::devices::{Keyboard, KB_MEM_SLOT};
+
+    fn mult(a: i16, b: i16) -> (Machine, AsmDebug) {
+        let (instructions, debug) = compile_file("resources/mult.asm", true).unwrap();
+        let mut machine = Machine::from_instructions(instructions);
+        machine.memory[0] = HackWord(a);
+        machine.memory[1] = HackWord(b);
+        (machine, debug.unwrap())
+    }
+
+    #[test]
+    fn breakpoints_stop_before_the_instruction() {
+        let (mut machine, debug) = mult(3, 2);
+        let pc = machine.add_label_breakpoint(&debug, "LOOP").unwrap();
+
+        // once on entering the loop, then once more per iteration
+        for _ in 0..3 {
+            assert_eq!(machine.run_until_break(1000), StopReason::Breakpoint(pc));
+            assert_eq!(machine.current_instruction, HackWord(pc as i16));
+        }
+        assert!(machine.remove_breakpoint(pc));
+        assert_eq!(machine.run_until_break(1000), StopReason::Halted);
+        assert_eq!(machine.memory[2], HackWord(6));
+    }
+
+    #[test]
+    fn line_breakpoints_skip_to_the_next_instruction() {
+        let (mut machine, debug) = mult(3, 2);
+
+        // line 8 is `(LOOP)`, line 9 is blank and line 10 is `@R1`
+        let pc = machine.add_line_breakpoint(&debug, 8).unwrap();
+
+        assert_eq!(debug.label_address("LOOP"), Some(pc));
+        assert_eq!(machine.run_until_break(1000), StopReason::Breakpoint(pc));
+        assert_eq!(machine.pc(), pc);
+        assert_eq!(debug.line_address(10), Some(pc));
+        assert!(machine.add_line_breakpoint(&debug, 100).is_err());
+        assert!(machine.add_label_breakpoint(&debug, "NOWHERE").is_err());
+    }
+
+    #[test]
+    fn write_watchpoints_report_old_and_new_values() {
+        let (mut machine, _) = mult(3, 2);
+        machine.add_watchpoint(Watchpoint::address(2, WatchKind::Write));
+
+        assert_eq!(
+            machine.run_until_break(1000),
+            StopReason::Watchpoint {
+                address: 2,
+                kind: WatchKind::Write,
+                old: HackWord(0),
+                new: HackWord(3),
+            }
+        );
+        assert_eq!(
+            machine.run_until_break(1000),
+            StopReason::Watchpoint {
+                address: 2,
+                kind: WatchKind::Write,
+                old: HackWord(3),
+                new: HackWord(6),
+            }
+        );
+        assert_eq!(machine.run_until_break(1000), StopReason::Halted);
+    }
+
+    #[test]
+    fn read_watchpoints_cover_ranges() {
+        let (instructions, _) = compile(
+            ["@100", "D=A", "@200", "M=D", "@201", "D=M"]
+                .map(String::from)
+                .to_vec(),
+            false,
+        )
+        .unwrap();
+        let mut machine = Machine::from_instructions(instructions);
+        machine.memory[201] = HackWord(7);
+        let watchpoint = Watchpoint::range(150..=250, WatchKind::Read);
+        machine.add_watchpoint(watchpoint.clone());
+
+        // the write to 200 is ignored
+        assert_eq!(
+            machine.run_until_break(1000),
+            StopReason::Watchpoint {
+                address: 201,
+                kind: WatchKind::Read,
+                old: HackWord(7),
+                new: HackWord(7),
+            }
+        );
+        assert!(machine.remove_watchpoint(&watchpoint));
+        assert!(!machine.remove_watchpoint(&watchpoint));
+    }
+
+    #[test]
+    fn read_watchpoints_see_device_values() {
+        let mut machine = Machine::from_instructions(compile_lines("@KBD\nD=M").unwrap());
+        machine.attach(Keyboard::default()).unwrap();
+        machine
+            .device_mut::<Keyboard>()
+            .unwrap()
+            .set_key(HackWord(65));
+        machine.add_watchpoint(Watchpoint::address(KB_MEM_SLOT, WatchKind::Read));
+
+        assert_eq!(
+            machine.run_until_break(1000),
+            StopReason::Watchpoint {
+                address: KB_MEM_SLOT,
+                kind: WatchKind::Read,
+                old: HackWord(65),
+                new: HackWord(65),
+            }
+        );
+    }
+
+    #[test]
+    fn step_limit() {
+        let (mut machine, _) = mult(3, 2);
+
+        assert_eq!(machine.run_until_break(5), StopReason::StepLimit);
+        assert_eq!(machine.current_instruction, HackWord(5));
+    }
+}
@@ -0,0 +1,214 @@
+use std::any::Any;
+use std::ops::RangeInclusive;
+
+use super::{Machine, MEMORY_SIZE};
+use crate::common::Res;
+use crate::hackword::HackWord;
+
+/// A memory-mapped peripheral.
+///
+/// Devices sit on top of RAM: words in a device's range are still stored in
+/// [`Machine::memory`], but the device decides what the CPU reads there and what gets
+/// stored when the CPU writes. Addresses below every device's range never consult a
+/// device, so plain RAM access costs a single comparison.
+pub trait Device: Any + Send {
+    /// The addresses the device claims
+    fn range(&self) -> RangeInclusive<u16>;
+
+    /// The value the CPU reads from `address`, given the word stored in RAM there
+    fn read(&mut self, _address: u16, stored: HackWord) -> HackWord {
+        stored
+    }
+
+    /// Called when the CPU writes `value` to `address`, returning the word to store in RAM
+    fn write(&mut self, _address: u16, value: HackWord) -> HackWord {
+        value
+    }
+
+    /// Called when stepping backwards puts `value` back in RAM at `address`. Unlike a CPU
+    /// write, the device can't refuse it or treat it as new output.
+    fn restore(&mut self, _address: u16, _value: HackWord) {}
+
+    /// The device's own state for a snapshot, beyond the words it keeps in RAM
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restores state from [`Device::save_state`], after RAM has been restored. An empty
+    /// state, from a snapshot without this device, resets it.
+    fn load_state(&mut self, _state: &[u8]) -> Res {
+        Ok(())
+    }
+}
+
+impl Machine {
+    /// Attaches a device, failing if its range overlaps an attached device or lies outside
+    /// memory
+    pub fn attach(&mut self, device: impl Device) -> Res {
+        let range = device.range();
+        if range.is_empty() || *range.end() as usize >= MEMORY_SIZE {
+            return Err(format!("Device range {range:?} is outside memory").into());
+        }
+        if let Some(other) = self.devices.iter().find(|d| {
+            let other = d.range();
+            range.start() <= other.end() && other.start() <= range.end()
+        }) {
+            let other = other.range();
+            return Err(format!("Device range {range:?} overlaps {other:?}").into());
+        }
+
+        self.mmio_floor = self.mmio_floor.min(*range.start());
+        self.devices.push(Box::new(device));
+        Ok(())
+    }
+
+    /// Detaches and returns the attached `T`, if there is one
+    pub fn detach<T: Device>(&mut self) -> Option<T> {
+        let index = self
+            .devices
+            .iter()
+            .position(|d| (d.as_ref() as &dyn Any).is::<T>())?;
+        let device: Box<dyn Any> = self.devices.remove(index);
+        self.mmio_floor = self
+            .devices
+            .iter()
+            .map(|d| *d.range().start())
+            .min()
+            .unwrap_or(MEMORY_SIZE as u16);
+        device.downcast().ok().map(|device| *device)
+    }
+
+    /// The attached `T`, if there is one
+    pub fn device<T: Device>(&self) -> Option<&T> {
+        self.devices
+            .iter()
+            .find_map(|d| (d.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    pub fn device_mut<T: Device>(&mut self) -> Option<&mut T> {
+        self.devices
+            .iter_mut()
+            .find_map(|d| (d.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// Reads memory as the CPU would, through any device at `address`
+    pub fn peek(&mut self, address: u16) -> HackWord {
+        self.load(address % MEMORY_SIZE as u16)
+    }
+
+    /// Writes memory as the CPU would, through any device at `address`. Undo history can't
+    /// reverse the write, so it's cleared.
+    pub fn poke(&mut self, address: u16, value: HackWord) {
+        self.clear_history();
+        self.store(address % MEMORY_SIZE as u16, value);
+    }
+
+    /// Reads a bus address (already below `MEMORY_SIZE`)
+    #[inline(always)]
+    pub(super) fn load(&mut self, address: u16) -> HackWord {
+        let stored = self.memory[address as usize];
+        if address < self.mmio_floor {
+            return stored;
+        }
+        match self
+            .devices
+            .iter_mut()
+            .find(|d| d.range().contains(&address))
+        {
+            Some(device) => device.read(address, stored),
+            None => stored,
+        }
+    }
+
+    /// Writes a bus address, returning the old and new stored words
+    #[inline(always)]
+    pub(super) fn store(&mut self, address: u16, value: HackWord) -> (HackWord, HackWord) {
+        let value = if address < self.mmio_floor {
+            value
+        } else {
+            match self
+                .devices
+                .iter_mut()
+                .find(|d| d.range().contains(&address))
+            {
+                Some(device) => device.write(address, value),
+                None => value,
+            }
+        };
+        let old = std::mem::replace(&mut self.memory[address as usize], value);
+        (old, value)
+    }
+
+    /// Puts back a word an undone instruction overwrote, letting any device at `address`
+    /// know. Returns the word it replaced.
+    pub(super) fn restore(&mut self, address: u16, value: HackWord) -> HackWord {
+        if address >= self.mmio_floor {
+            if let Some(device) = self
+                .devices
+                .iter_mut()
+                .find(|d| d.range().contains(&address))
+            {
+                device.restore(address, value);
+            }
+        }
+        std::mem::replace(&mut self.memory[address as usize], value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::compile_lines;
+
+    /// Counts up every time it is read, and ignores writes
+    #[derive(Default)]
+    struct Timer {
+        ticks: i16,
+        writes: Vec<HackWord>,
+    }
+
+    impl Device for Timer {
+        fn range(&self) -> RangeInclusive<u16> {
+            0x7000..=0x7000
+        }
+
+        fn read(&mut self, _: u16, _: HackWord) -> HackWord {
+            self.ticks += 1;
+            HackWord(self.ticks)
+        }
+
+        fn write(&mut self, _: u16, value: HackWord) -> HackWord {
+            self.writes.push(value);
+            HackWord::zero()
+        }
+    }
+
+    #[test]
+    fn devices_intercept_reads_and_writes() {
+        let mut machine = Machine::from_instructions(
+            compile_lines("@28672\nD=M\nD=D+M\n@0\nM=D\n@28672\nM=D").unwrap(),
+        );
+        machine.attach(Timer::default()).unwrap();
+        machine.run();
+
+        assert_eq!(machine.memory[0], HackWord(3));
+        assert_eq!(machine.memory[0x7000], HackWord::zero());
+        assert_eq!(machine.device::<Timer>().unwrap().writes, [HackWord(3)]);
+        assert_eq!(machine.peek(0x7000), HackWord(3));
+
+        machine.poke(0x7000, HackWord(9));
+        let timer = machine.detach::<Timer>().unwrap();
+        assert_eq!(timer.writes, [HackWord(3), HackWord(9)]);
+        assert_eq!(machine.mmio_floor, MEMORY_SIZE as u16);
+        assert!(machine.device::<Timer>().is_none());
+    }
+
+    #[test]
+    fn overlapping_devices_are_rejected() {
+        let mut machine = Machine::new();
+        machine.attach(Timer::default()).unwrap();
+
+        assert!(machine.attach(Timer::default()).is_err());
+        assert_eq!(machine.devices.len(), 1);
+    }
+}
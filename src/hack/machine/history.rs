@@ -0,0 +1,305 @@
+//! An undo log for stepping the machine backwards.
+//!
+//! Each recorded step keeps just enough to reverse it: the PC, A and D from before the
+//! instruction ran, and the word it overwrote if it wrote to memory. Writes made directly
+//! to [`Machine::memory`] are not recorded, and changes made from outside the program, such
+//! as [`Machine::set_register_a`], clear the log since they can't be undone.
+
+use std::collections::VecDeque;
+use std::mem::size_of;
+
+use super::{Machine, MemoryWrite, StopReason, WatchKind};
+use crate::common::Res;
+use crate::hackword::HackWord;
+
+#[derive(Clone, Copy)]
+struct UndoEntry {
+    pc: u16,
+    a: HackWord,
+    d: HackWord,
+    /// The address written and the value it held before
+    write: Option<(u16, HackWord)>,
+}
+
+pub(super) struct History {
+    entries: VecDeque<UndoEntry>,
+    capacity: usize,
+}
+
+impl History {
+    fn new(budget: usize) -> Self {
+        // allocated up front, as growing by doubling could reach twice the budget
+        let capacity = budget / size_of::<UndoEntry>();
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, entry: UndoEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl Machine {
+    /// Starts keeping an undo log of at most `budget` bytes, forgetting the oldest steps once
+    /// it fills up. If already recording, the newest steps that fit the new budget are kept.
+    pub fn record_history(&mut self, budget: usize) {
+        let mut history = History::new(budget);
+        if let Some(old) = self.history.take() {
+            let skip = old.entries.len().saturating_sub(history.capacity);
+            history.entries.extend(old.entries.into_iter().skip(skip));
+        }
+        self.history = Some(history);
+    }
+
+    /// Forgets every recorded step, for changes the log has no way to undo
+    pub(super) fn clear_history(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+
+    /// Stops recording and drops the undo log
+    pub fn stop_recording(&mut self) {
+        self.history = None;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// The oldest cycle the machine can step back to, or `None` if it isn't recording
+    pub fn earliest_cycle(&self) -> Option<u64> {
+        let history = self.history.as_ref()?;
+        Some(self.cycles - history.entries.len() as u64)
+    }
+
+    pub(super) fn record(&mut self, pc: u16, a: HackWord, d: HackWord, write: Option<MemoryWrite>) {
+        if let Some(history) = &mut self.history {
+            history.push(UndoEntry {
+                pc,
+                a,
+                d,
+                write: write.map(|w| (w.address, w.old)),
+            });
+        }
+    }
+
+    /// Undoes the last recorded step, returning `false` if there is nothing left to undo
+    pub fn step_back(&mut self) -> bool {
+        self.undo().is_some()
+    }
+
+    /// Undoes one step, returning the write it reverted as it originally happened
+    fn undo(&mut self) -> Option<Option<MemoryWrite>> {
+        let entry = self.history.as_mut()?.entries.pop_back()?;
+        self.current_instruction = HackWord::from_u16(entry.pc);
+        self.register_a = entry.a;
+        self.register_d = entry.d;
+        self.cycles -= 1;
+        Some(entry.write.map(|(address, old)| {
+            let new = self.restore(address, old);
+            MemoryWrite { address, old, new }
+        }))
+    }
+
+    /// Steps backwards until the PC reaches a breakpoint or an instruction that wrote to a
+    /// watched address, which is left about to run again. At least one step is undone, so
+    /// calling this again keeps going back.
+    pub fn reverse_continue(&mut self) -> StopReason {
+        while let Some(write) = self.undo() {
+            let pc = self.pc();
+            if let Some(write) = write {
+                let watched = self
+                    .watchpoints
+                    .iter()
+                    .any(|w| w.range.contains(&write.address) && w.kind != WatchKind::Read);
+                if watched {
+                    return StopReason::Watchpoint {
+                        address: write.address,
+                        kind: WatchKind::Write,
+                        old: write.old,
+                        new: write.new,
+                    };
+                }
+            }
+            if self.breakpoints.contains(&pc) {
+                return StopReason::Breakpoint(pc);
+            }
+        }
+        StopReason::HistoryStart
+    }
+
+    /// Steps backwards or forwards until `cycles()` is `cycle`. Breakpoints and watchpoints
+    /// are ignored.
+    pub fn goto_cycle(&mut self, cycle: u64) -> Res {
+        if cycle < self.cycles {
+            match self.earliest_cycle() {
+                Some(earliest) if earliest <= cycle => {
+                    while self.cycles > cycle {
+                        self.undo();
+                    }
+                }
+                Some(earliest) => {
+                    return Err(format!(
+                        "Cycle {cycle} is before the oldest recorded step (cycle {earliest})"
+                    )
+                    .into())
+                }
+                None => return Err("Not recording history, so can't go back".into()),
+            }
+        }
+        while self.cycles < cycle {
+            if !self.step() {
+                return Err(format!("The program halted at cycle {}", self.cycles).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{compile_file, compile_lines};
+    use crate::devices::{Screen, SCREEN_MEM_START};
+    use crate::machine::Watchpoint;
+
+    fn mult(a: i16, b: i16, budget: usize) -> Machine {
+        let (instructions, _) = compile_file("resources/mult.asm", false).unwrap();
+        let mut machine = Machine::from_instructions(instructions);
+        machine.memory[0] = HackWord(a);
+        machine.memory[1] = HackWord(b);
+        machine.record_history(budget);
+        machine
+    }
+
+    fn state(machine: &Machine) -> (u16, HackWord, HackWord, Vec<HackWord>) {
+        (
+            machine.pc(),
+            machine.register_a,
+            machine.register_d,
+            machine.memory[..32].to_vec(),
+        )
+    }
+
+    #[test]
+    fn stepping_back_restores_every_state() {
+        let mut machine = mult(3, 4, 1 << 20);
+        let mut states = vec![state(&machine)];
+        while machine.step() {
+            states.push(state(&machine));
+        }
+        assert_eq!(machine.cycles(), states.len() as u64 - 1);
+        assert_eq!(machine.memory[2], HackWord(12));
+
+        while let Some(expected) = states.pop() {
+            assert_eq!(state(&machine), expected, "cycle {}", machine.cycles());
+            machine.step_back();
+        }
+        assert!(!machine.step_back());
+        assert_eq!(machine.cycles(), 0);
+    }
+
+    #[test]
+    fn reverse_continue_finds_the_last_write() {
+        let mut machine = mult(3, 4, 1 << 20);
+        machine.run();
+        machine.add_watchpoint(Watchpoint::address(2, WatchKind::Write));
+
+        // the loop body's `M=D+M` at pc 12 is the last instruction to write R2
+        assert_eq!(
+            machine.reverse_continue(),
+            StopReason::Watchpoint {
+                address: 2,
+                kind: WatchKind::Write,
+                old: HackWord(9),
+                new: HackWord(12),
+            }
+        );
+        assert_eq!(machine.pc(), 12);
+        assert_eq!(machine.memory[2], HackWord(9));
+
+        machine.watchpoints.clear();
+        machine.add_breakpoint(4);
+        assert_eq!(machine.reverse_continue(), StopReason::Breakpoint(4));
+        machine.remove_breakpoint(4);
+        assert_eq!(machine.reverse_continue(), StopReason::HistoryStart);
+        assert_eq!(machine.cycles(), 0);
+    }
+
+    #[test]
+    fn goto_cycle_moves_both_ways() {
+        let mut machine = mult(3, 4, 1 << 20);
+        machine.goto_cycle(20).unwrap();
+        let at_20 = state(&machine);
+
+        machine.goto_cycle(30).unwrap();
+        assert_eq!(machine.cycles(), 30);
+        machine.goto_cycle(20).unwrap();
+        assert_eq!(state(&machine), at_20);
+
+        assert!(machine.goto_cycle(100_000).is_err());
+    }
+
+    #[test]
+    fn undoing_screen_writes_marks_them_dirty() {
+        let mut machine = Machine::from_instructions(compile_lines("@SCREEN\nM=-1").unwrap());
+        machine.attach(Screen::default()).unwrap();
+        machine.record_history(1 << 20);
+        machine.run();
+        machine.device_mut::<Screen>().unwrap().take_dirty();
+
+        machine.step_back();
+        assert_eq!(machine.memory[SCREEN_MEM_START as usize], HackWord(0));
+        let screen = machine.device_mut::<Screen>().unwrap();
+        assert_eq!(screen.take_dirty().iter().collect::<Vec<_>>(), [0]);
+    }
+
+    #[test]
+    fn outside_changes_clear_the_log() {
+        let mut machine = mult(3, 4, 1 << 20);
+        machine.run();
+        let end = machine.cycles();
+
+        // ticking past the end of the program is recorded like any other step
+        machine.tick();
+        assert_eq!(machine.earliest_cycle(), Some(0));
+        assert!(machine.step_back());
+        assert_eq!(machine.cycles(), end);
+
+        machine.set_register_d(HackWord(5));
+        assert_eq!(machine.earliest_cycle(), Some(end));
+        machine.tick();
+        machine.poke(2, HackWord(1));
+        assert_eq!(machine.earliest_cycle(), Some(end + 1));
+    }
+
+    #[test]
+    fn the_budget_bounds_the_log() {
+        let mut machine = mult(3, 4, 10 * size_of::<UndoEntry>());
+        machine.run();
+
+        let end = machine.cycles();
+        assert_eq!(machine.earliest_cycle(), Some(end - 10));
+        assert!(machine.goto_cycle(end - 11).is_err());
+        machine.goto_cycle(end - 10).unwrap();
+        assert!(!machine.step_back());
+
+        machine.record_history(4 * size_of::<UndoEntry>());
+        assert_eq!(machine.earliest_cycle(), Some(end - 10));
+        machine.stop_recording();
+        assert_eq!(machine.earliest_cycle(), None);
+    }
+}
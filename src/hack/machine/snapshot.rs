@@ -0,0 +1,231 @@
+//! Saving and restoring the whole machine state.
+//!
+//! A snapshot is little-endian throughout:
+//!
+//! | field     | size                        |
+//! |-----------|-----------------------------|
+//! | magic     | 8 bytes, `HACKSNAP`         |
+//! | version   | u16                         |
+//! | semantics | u8, 0 conformant, 1 legacy  |
+//! | PC, A, D  | u16 each                    |
+//! | cycles    | u64                         |
+//! | ROM size  | u32, then that many u16s    |
+//! | RAM       | `MEMORY_SIZE` u16s          |
+//! | devices   | u16 count, then each device |
+//!
+//! Each device is its first address as a u16, then its state from
+//! [`Device::save_state`](super::Device::save_state)
+//! as a u32 length and that many bytes. Version 1 snapshots end after RAM.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::{Machine, Semantics, MEMORY_SIZE, ROM_SIZE};
+use crate::common::Res;
+use crate::hackword::HackWord;
+
+const MAGIC: &[u8; 8] = b"HACKSNAP";
+const VERSION: u16 = 2;
+
+impl Machine {
+    /// Writes the ROM, RAM, registers, PC, cycle count and the attached devices' state.
+    /// Breakpoints, watchpoints and undo history are not saved.
+    pub fn save_snapshot(&self, mut writer: impl Write) -> Res {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&[match self.semantics {
+            Semantics::Conformant => 0,
+            Semantics::Legacy => 1,
+        }])?;
+        for word in [self.current_instruction, self.register_a, self.register_d] {
+            writer.write_all(&word.as_u16().to_le_bytes())?;
+        }
+        writer.write_all(&self.cycles.to_le_bytes())?;
+        writer.write_all(&(self.instructions.len() as u32).to_le_bytes())?;
+        for word in self.instructions.iter().chain(&self.memory) {
+            writer.write_all(&word.as_u16().to_le_bytes())?;
+        }
+        writer.write_all(&(self.devices.len() as u16).to_le_bytes())?;
+        for device in &self.devices {
+            let state = device.save_state();
+            writer.write_all(&device.range().start().to_le_bytes())?;
+            writer.write_all(&(state.len() as u32).to_le_bytes())?;
+            writer.write_all(&state)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Replaces the machine's state with a snapshot written by [`Machine::save_snapshot`].
+    /// Breakpoints and watchpoints are kept, and undo history is cleared. Attached devices
+    /// the snapshot has no state for are reset, and its state for devices that aren't
+    /// attached is ignored.
+    pub fn load_snapshot(&mut self, mut reader: impl Read) -> Res {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("Not a snapshot file".into());
+        }
+        let version = u16::from_le_bytes(read_array(&mut reader)?);
+        if !(1..=VERSION).contains(&version) {
+            return Err(format!("Unsupported snapshot version {version}").into());
+        }
+        let semantics = match read_array(&mut reader)? {
+            [0] => Semantics::Conformant,
+            [1] => Semantics::Legacy,
+            [x] => return Err(format!("Unknown semantics {x} in snapshot").into()),
+        };
+        let pc = read_word(&mut reader)?;
+        let a = read_word(&mut reader)?;
+        let d = read_word(&mut reader)?;
+        let cycles = u64::from_le_bytes(read_array(&mut reader)?);
+        let rom_size = u32::from_le_bytes(read_array(&mut reader)?) as usize;
+        if rom_size > ROM_SIZE {
+            return Err(format!("Snapshot ROM has {rom_size} words, more than fit").into());
+        }
+        let rom = (0..rom_size)
+            .map(|_| read_word(&mut reader))
+            .collect::<Res<Vec<_>>>()?;
+        let mut memory = [HackWord::zero(); MEMORY_SIZE];
+        for slot in &mut memory {
+            *slot = read_word(&mut reader)?;
+        }
+        let mut states = Vec::new();
+        if version >= 2 {
+            let count = u16::from_le_bytes(read_array(&mut reader)?);
+            for _ in 0..count {
+                let start = u16::from_le_bytes(read_array(&mut reader)?);
+                let len = u32::from_le_bytes(read_array(&mut reader)?) as usize;
+                let mut state = Vec::new();
+                reader.by_ref().take(len as u64).read_to_end(&mut state)?;
+                if state.len() != len {
+                    return Err("Snapshot ends in the middle of a device's state".into());
+                }
+                states.push((start, state));
+            }
+        }
+
+        self.load_instructions(rom);
+        self.memory = memory;
+        self.semantics = semantics;
+        self.set_instruction(pc);
+        self.register_a = a;
+        self.register_d = d;
+        self.cycles = cycles;
+        for device in &mut self.devices {
+            let start = *device.range().start();
+            let state = states.iter().find(|(s, _)| *s == start);
+            device.load_state(state.map_or(&[], |(_, state)| state))?;
+        }
+        Ok(())
+    }
+
+    pub fn save_snapshot_file(&self, path: impl AsRef<Path>) -> Res {
+        self.save_snapshot(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn load_snapshot_file(&mut self, path: impl AsRef<Path>) -> Res {
+        self.load_snapshot(BufReader::new(File::open(path)?))
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Res<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_word(reader: &mut impl Read) -> Res<HackWord> {
+    Ok(HackWord::from_u16(u16::from_le_bytes(read_array(reader)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::compile_file;
+    use crate::devices::{Console, Keyboard, CONSOLE_ADDRESS};
+
+    fn mult(a: i16, b: i16) -> Machine {
+        let (instructions, _) = compile_file("resources/mult.asm", false).unwrap();
+        let mut machine = Machine::from_instructions(instructions);
+        machine.memory[0] = HackWord(a);
+        machine.memory[1] = HackWord(b);
+        machine
+    }
+
+    #[test]
+    fn snapshots_resume_where_they_left_off() {
+        let mut machine = mult(6, 7);
+        machine.set_semantics(Semantics::Legacy);
+        for _ in 0..20 {
+            machine.step();
+        }
+        let mut snapshot = Vec::new();
+        machine.save_snapshot(&mut snapshot).unwrap();
+
+        let mut restored = Machine::new();
+        restored.load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.rom(), machine.rom());
+        assert_eq!(restored.pc(), machine.pc());
+        assert_eq!(restored.register_a, machine.register_a);
+        assert_eq!(restored.register_d, machine.register_d);
+        assert_eq!(restored.cycles(), 20);
+        assert_eq!(restored.semantics(), Semantics::Legacy);
+
+        machine.run();
+        restored.run();
+        assert_eq!(restored.memory, machine.memory);
+        assert_eq!(restored.memory[2], HackWord(42));
+        assert_eq!(restored.cycles(), machine.cycles());
+    }
+
+    #[test]
+    fn bad_snapshots_are_rejected() {
+        let mut snapshot = Vec::new();
+        mult(1, 2).save_snapshot(&mut snapshot).unwrap();
+        let mut machine = Machine::new();
+
+        assert!(machine.load_snapshot(&b"HACKSNIP"[..]).is_err());
+        assert!(machine
+            .load_snapshot(&snapshot[..snapshot.len() - 1])
+            .is_err());
+
+        let mut future = snapshot.clone();
+        future[8] = 3;
+        let error = machine.load_snapshot(future.as_slice()).unwrap_err();
+        assert_eq!(error.to_string(), "Unsupported snapshot version 3");
+    }
+
+    #[test]
+    fn device_state_is_saved() {
+        let with_devices = || {
+            let mut machine = mult(1, 2);
+            machine.attach(Keyboard::default()).unwrap();
+            machine.attach(Console::default()).unwrap();
+            machine
+        };
+        let mut machine = with_devices();
+        machine
+            .device_mut::<Keyboard>()
+            .unwrap()
+            .set_key(HackWord(65));
+        machine.poke(CONSOLE_ADDRESS, HackWord(b'h'.into()));
+        let mut snapshot = Vec::new();
+        machine.save_snapshot(&mut snapshot).unwrap();
+
+        let mut restored = with_devices();
+        restored.load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.device::<Keyboard>().unwrap().key(), HackWord(65));
+        assert_eq!(restored.device::<Console>().unwrap().text(), "h");
+
+        // version 1 snapshots have no devices, so they're reset
+        let mut old = Vec::new();
+        mult(1, 2).save_snapshot(&mut old).unwrap();
+        old.truncate(old.len() - 2);
+        old[8] = 1;
+        restored.load_snapshot(old.as_slice()).unwrap();
+        assert_eq!(restored.device::<Keyboard>().unwrap().key(), HackWord(0));
+        assert_eq!(restored.device::<Console>().unwrap().text(), "");
+    }
+}
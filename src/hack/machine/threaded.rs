@@ -0,0 +1,355 @@
+//! A basic-block execution engine.
+//!
+//! The ROM is split into blocks that end at a jump or just before a jump target. Each block
+//! is compiled into a sequence of handlers specialised for the instruction's destinations,
+//! so running a block is a straight walk through function pointers with no decoding.
+//! Blocks are built lazily, so jumps to computed addresses that land mid-block still work:
+//! they just get a block of their own.
+
+use super::{bus_address, Machine, Op, Semantics, ROM_SIZE};
+use crate::hackword::HackWord;
+use crate::instruction::{Comp, Jump};
+
+type Handler = fn(&mut Machine, Op);
+
+#[derive(Clone, Copy)]
+struct Threaded {
+    handler: Handler,
+    op: Op,
+}
+
+type Block = Box<[Threaded]>;
+
+pub(super) struct BlockCache {
+    leaders: Vec<bool>,
+    blocks: Vec<Option<Block>>,
+}
+
+impl BlockCache {
+    pub(super) fn new(program: &[Op], len: usize) -> Self {
+        let len = len.min(ROM_SIZE);
+        let mut leaders = vec![false; len];
+        if len > 0 {
+            leaders[0] = true;
+        }
+        for (i, op) in program[..len].iter().enumerate() {
+            if let Op::Compute { jump, .. } = op {
+                if *jump == Jump::Null {
+                    continue;
+                }
+                if i + 1 < len {
+                    leaders[i + 1] = true;
+                }
+                // `@LABEL` followed by a jump is the only statically known target
+                if let Some(Op::LoadA(target) | Op::Halt(target)) =
+                    i.checked_sub(1).map(|p| program[p])
+                {
+                    if let Some(leader) = leaders.get_mut(target.to_usize()) {
+                        *leader = true;
+                    }
+                }
+            }
+        }
+
+        Self {
+            leaders,
+            blocks: vec![None; len],
+        }
+    }
+
+    /// The block starting at `pc`, or `None` if it is outside the program
+    fn block(&mut self, pc: usize, program: &[Op]) -> Option<&[Threaded]> {
+        let leaders = &self.leaders;
+        let block = self.blocks.get_mut(pc)?.get_or_insert_with(|| {
+            let mut code = Vec::new();
+            for (i, &op) in program.iter().enumerate().take(leaders.len()).skip(pc) {
+                if i != pc && leaders[i] {
+                    break;
+                }
+                code.push(compile(op));
+                if matches!(op, Op::Compute { jump, .. } if jump != Jump::Null) {
+                    break;
+                }
+            }
+            code.into_boxed_slice()
+        });
+        Some(block)
+    }
+}
+
+fn compile(op: Op) -> Threaded {
+    let handler: Handler = match op {
+        Op::LoadA(_) => load_a,
+        Op::Compute {
+            should_deref,
+            dest,
+            jump: Jump::Null,
+            ..
+        } => match (should_deref, dest.a, dest.d, dest.m) {
+            (false, false, false, false) => compute::<false, false, false, false>,
+            (false, false, false, true) => compute::<false, false, false, true>,
+            (false, false, true, false) => compute::<false, false, true, false>,
+            (false, false, true, true) => compute::<false, false, true, true>,
+            (false, true, false, false) => compute::<false, true, false, false>,
+            (false, true, false, true) => compute::<false, true, false, true>,
+            (false, true, true, false) => compute::<false, true, true, false>,
+            (false, true, true, true) => compute::<false, true, true, true>,
+            (true, false, false, false) => compute::<true, false, false, false>,
+            (true, false, false, true) => compute::<true, false, false, true>,
+            (true, false, true, false) => compute::<true, false, true, false>,
+            (true, false, true, true) => compute::<true, false, true, true>,
+            (true, true, false, false) => compute::<true, true, false, false>,
+            (true, true, false, true) => compute::<true, true, false, true>,
+            (true, true, true, false) => compute::<true, true, true, false>,
+            (true, true, true, true) => compute::<true, true, true, true>,
+        },
+        // jumps end the block, and go through the interpreter so they behave identically
+        Op::Compute { .. } | Op::Trap | Op::Halt(_) => interpret,
+    };
+    Threaded { handler, op }
+}
+
+fn load_a(machine: &mut Machine, op: Op) {
+    if let Op::LoadA(value) = op {
+        machine.register_a = value;
+        machine.set_instruction(machine.current_instruction + HackWord::one());
+        machine.cycles += 1;
+    }
+}
+
+fn compute<const DEREF: bool, const A: bool, const D: bool, const M: bool>(
+    machine: &mut Machine,
+    op: Op,
+) {
+    let comp: Comp = match op {
+        Op::Compute { comp, .. } => comp,
+        _ => return,
+    };
+    let a = if DEREF {
+        machine.m()
+    } else {
+        machine.register_a
+    };
+    let value = comp.compute(machine.register_d, a);
+
+    let address = if A && M && machine.semantics == Semantics::Legacy {
+        value
+    } else {
+        machine.register_a
+    };
+    if A {
+        machine.register_a = value;
+    }
+    if D {
+        machine.register_d = value;
+    }
+    if M {
+        machine.store(bus_address(address), value);
+    }
+    machine.set_instruction(machine.current_instruction + HackWord::one());
+    machine.cycles += 1;
+}
+
+fn interpret(machine: &mut Machine, _: Op) {
+    machine.step();
+}
+
+impl Machine {
+    /// Runs until the PC leaves the program, like [`Machine::run`], using the basic-block
+    /// engine. Blocks don't record undo history or trace, so this falls back to the
+    /// interpreter while either is on.
+    pub fn run_threaded(&mut self) {
+        if self.is_observed() {
+            while self.step() {}
+            return;
+        }
+
+        let mut cache = self
+            .blocks
+            .take()
+            .unwrap_or_else(|| BlockCache::new(&self.program, self.instructions.len()));
+
+        // halting loops are always leaders, as the target of the jump that follows them,
+        // so halting is only possible at the start of a block
+        while !self.is_halted() {
+            let pc = self.current_instruction.to_usize() % ROM_SIZE;
+            let Some(block) = cache.block(pc, &self.program) else {
+                break;
+            };
+            for threaded in block {
+                (threaded.handler)(self, threaded.op);
+            }
+        }
+
+        self.blocks = Some(cache);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::asm::{compile_file, compile_lines};
+    use crate::instruction::{Dest, Instruction};
+    use crate::machine::MEMORY_SIZE;
+
+    fn assert_same_state(interpreted: &Machine, threaded: &Machine) {
+        assert_eq!(interpreted.register_a, threaded.register_a, "A");
+        assert_eq!(interpreted.register_d, threaded.register_d, "D");
+        assert_eq!(
+            interpreted.current_instruction, threaded.current_instruction,
+            "PC"
+        );
+        assert_eq!(interpreted.cycles, threaded.cycles, "cycles");
+        for (i, (x, y)) in interpreted.memory.iter().zip(&threaded.memory).enumerate() {
+            assert_eq!(x, y, "RAM[{i}]");
+        }
+    }
+
+    /// Runs both engines from the same starting RAM and checks they end up identical
+    fn differential(instructions: Vec<HackWord>, ram: &[(usize, i16)], semantics: Semantics) {
+        let setup = || {
+            let mut machine = Machine::from_instructions(instructions.clone());
+            machine.set_semantics(semantics);
+            for &(address, value) in ram {
+                machine.memory[address] = HackWord(value);
+            }
+            machine
+        };
+
+        let mut interpreted = setup();
+        while interpreted.step() {}
+        let mut threaded = setup();
+        threaded.run_threaded();
+
+        assert_same_state(&interpreted, &threaded);
+    }
+
+    #[test]
+    fn resource_programs() {
+        for (a, b) in [(0, 3), (3, 0), (7, 9), (-2, 5)] {
+            let ram = [(0, a), (1, b)];
+            for file in ["resources/mult.asm", "resources/max.asm"] {
+                let (instructions, _) = compile_file(file, false).unwrap();
+                differential(instructions, &ram, Semantics::Conformant);
+            }
+        }
+    }
+
+    #[test]
+    fn screen_writes_and_computed_jumps() {
+        // fills a row of the screen through a pointer, then jumps to a computed address
+        // that lands in the middle of a block
+        let asm = "
+            @SCREEN
+            D=A
+            @ptr
+            M=D
+        (LOOP)
+            @ptr
+            M=M+1
+            A=M
+            M=-1
+            D=A
+            @16416
+            D=D-A
+            @LOOP
+            D;JLT
+            @22
+            D=A
+            @3
+            A=D-A
+            0;JMP
+            @KBD
+            M=1
+            D=M
+            @0
+            M=D
+        ";
+        for semantics in [Semantics::Conformant, Semantics::Legacy] {
+            differential(compile_lines(asm).unwrap(), &[], semantics);
+        }
+    }
+
+    #[test]
+    fn halting_loops() {
+        let asm = "
+            @5
+            D=A
+        (LOOP)
+            D=D-1
+            @LOOP
+            D;JGT
+        (END)
+            @END
+            0;JMP
+        ";
+        differential(compile_lines(asm).unwrap(), &[], Semantics::Conformant);
+    }
+
+    #[test]
+    fn blocks_are_rebuilt_after_loading() {
+        let mut machine = Machine::from_instructions(compile_lines("@1\nD=A\n@0\nM=D").unwrap());
+        machine.run_threaded();
+        assert_eq!(machine.memory[0], HackWord(1));
+
+        machine.load_instructions(compile_lines("@2\nD=A\n@0\nM=D").unwrap());
+        machine.current_instruction = HackWord::zero();
+        machine.run_threaded();
+        assert_eq!(machine.memory[0], HackWord(2));
+    }
+
+    /// A random instruction that never jumps
+    fn straight_line() -> impl Strategy<Value = Vec<Instruction>> {
+        prop_oneof![
+            (0..MEMORY_SIZE as u16).prop_map(|x| vec![Instruction::A(x)]),
+            (
+                0..64u8,
+                any::<bool>(),
+                any::<bool>(),
+                any::<bool>(),
+                any::<bool>()
+            )
+                .prop_map(|(bits, should_deref, d, m, a)| vec![Instruction::C {
+                    comp: Comp::from_bits(bits),
+                    should_deref,
+                    dest: Dest { a, d, m },
+                    jump: Jump::Null,
+                }]),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn random_forward_programs(
+            chunks in prop::collection::vec(
+                (prop::collection::vec(straight_line(), 0..6), 0..8u8, 0..64u8, 1..8usize, any::<bool>()),
+                1..12,
+            ),
+            ram in prop::collection::vec((0..MEMORY_SIZE, any::<i16>()), 0..16),
+        ) {
+            // each chunk is straight-line code followed by a jump to the start of a later
+            // chunk, which loads its own jump target before using it
+            let mut starts = vec![0];
+            for (body, ..) in &chunks {
+                starts.push(starts.last().unwrap() + body.iter().map(Vec::len).sum::<usize>() + 2);
+            }
+            let mut program: Vec<Instruction> = Vec::new();
+            for (i, (body, jump, bits, skip, m)) in chunks.into_iter().enumerate() {
+                program.extend(body.into_iter().flatten());
+                let target = starts[(i + skip).min(starts.len() - 1)];
+                program.push(Instruction::A(target as u16));
+                program.push(Instruction::C {
+                    comp: Comp::from_bits(bits),
+                    should_deref: false,
+                    dest: Dest { a: false, d: true, m },
+                    jump: [Jump::Null, Jump::JGT, Jump::JEQ, Jump::JGE, Jump::JLT, Jump::JNE, Jump::JLE, Jump::JMP][jump as usize],
+                });
+            }
+
+            let instructions = program.into_iter().map(HackWord::from).collect();
+            differential(instructions, &ram, Semantics::Conformant);
+        }
+    }
+}
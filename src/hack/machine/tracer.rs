@@ -0,0 +1,137 @@
+use std::any::Any;
+
+use super::{Access, Machine, MemoryWrite};
+use crate::common::Res;
+use crate::hackword::HackWord;
+use crate::instruction::Instruction;
+
+/// One executed instruction, as seen by a [`Tracer`]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct TraceEvent {
+    /// The cycle the instruction ran in, counting from 0
+    pub cycle: u64,
+    pub pc: u16,
+    pub instruction: Instruction,
+    pub a_before: HackWord,
+    pub d_before: HackWord,
+    pub a_after: HackWord,
+    pub d_after: HackWord,
+    pub write: Option<MemoryWrite>,
+    /// The PC after the instruction
+    pub next_pc: u16,
+    /// Whether the instruction jumped, which it can do to `pc + 1`
+    pub jumped: bool,
+}
+
+/// Receives every instruction the machine executes
+pub trait Tracer: Any {
+    fn trace(&mut self, event: &TraceEvent);
+
+    /// Called once tracing is over, to flush output and report any errors
+    fn finish(&mut self) -> Res {
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&TraceEvent) + 'static> Tracer for F {
+    fn trace(&mut self, event: &TraceEvent) {
+        self(event)
+    }
+}
+
+impl Machine {
+    /// Calls `tracer` after each instruction from now on, replacing any previous tracer
+    pub fn set_tracer(&mut self, tracer: impl Tracer + Send + 'static) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    /// Stops tracing, handing back the tracer so it can be finished
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer + Send>> {
+        self.tracer.take()
+    }
+
+    /// Stops tracing and hands back the tracer if it is a `T`, or leaves it in place if not
+    pub fn take_tracer_as<T: Tracer>(&mut self) -> Option<T> {
+        let tracer: &dyn Any = self.tracer.as_deref()?;
+        if !tracer.is::<T>() {
+            return None;
+        }
+        let tracer: Box<dyn Any> = self.tracer.take()?;
+        tracer.downcast().ok().map(|tracer| *tracer)
+    }
+
+    pub(super) fn emit_trace(&mut self, pc: u16, a: HackWord, d: HackWord, access: Access) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&TraceEvent {
+                cycle: self.cycles - 1,
+                pc,
+                instruction: self.instructions[pc as usize].into(),
+                a_before: a,
+                d_before: d,
+                a_after: self.register_a,
+                d_after: self.register_d,
+                write: access.write,
+                next_pc: self.current_instruction.as_u16(),
+                jumped: access.jumped,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::asm::compile_lines;
+
+    #[test]
+    fn jumps_to_negative_addresses_wrap() {
+        // A=D+1 makes A -32768, which the 15-bit PC sees as address 0
+        let mut machine =
+            Machine::from_instructions(compile_lines("@32767\nD=A\nA=D+1\n0;JMP").unwrap());
+        let pcs = Arc::new(Mutex::new(Vec::new()));
+        let sink = pcs.clone();
+        machine.set_tracer(move |event: &TraceEvent| sink.lock().unwrap().push(event.pc));
+        machine.run_with_limit(6);
+
+        assert_eq!(*pcs.lock().unwrap(), [0, 1, 2, 3, 0, 1]);
+        assert_eq!(machine.pc(), 2);
+    }
+
+    #[test]
+    fn every_instruction_is_traced() {
+        let mut machine = Machine::from_instructions(compile_lines("@3\nD=A\n@0\nM=D").unwrap());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        machine.set_tracer(move |event: &TraceEvent| sink.lock().unwrap().push(*event));
+        machine.run();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(
+            events[1],
+            TraceEvent {
+                cycle: 1,
+                pc: 1,
+                instruction: machine.rom()[1].into(),
+                a_before: HackWord(3),
+                d_before: HackWord(0),
+                a_after: HackWord(3),
+                d_after: HackWord(3),
+                write: None,
+                next_pc: 2,
+                jumped: false,
+            }
+        );
+        assert_eq!(
+            events[3].write,
+            Some(MemoryWrite {
+                address: 0,
+                old: HackWord(0),
+                new: HackWord(3),
+            })
+        );
+        assert!(machine.take_tracer().unwrap().finish().is_ok());
+    }
+}
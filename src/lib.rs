@@ -0,0 +1,9 @@
+pub mod asm;
+pub mod common;
+pub mod debugger;
+pub mod disasm;
+pub mod hack;
+pub mod profile;
+pub mod trace;
+pub mod tst;
+pub use hack::*;
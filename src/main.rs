@@ -1,60 +1,433 @@
-mod common;
-use std::{ffi::OsStr, path::Path};
-
-use asm::compile_file;
-use common::*;
-mod hack;
-use hack::*;
-mod asm;
-use clap::Parser;
-use io::*;
-use machine::*;
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{BufReader, BufWriter},
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::{Parser, Subcommand};
+use hack_rs::{
+    asm::{compile, compile_file},
+    capture::{CapturePlan, ScreenImage, FRAME_CYCLES},
+    common::*,
+    debugger::Debugger,
+    devices::Console,
+    disasm::disassemble,
+    hackword::HackWord,
+    input::{InputPlayer, InputScript},
+    io::*,
+    keymap::KeyMap,
+    machine::*,
+    profile::Profiler,
+    trace::{diff_traces, TraceWriter},
+    tst::run_test,
+};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    /// Whether to run in windowed mode
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Run without a window until the program halts
     #[arg(long, default_value_t = false)]
     quiet: bool,
 
     /// Path to instruction file
-    file: String,
+    #[arg(
+        required_unless_present = "load_snapshot",
+        conflicts_with = "load_snapshot"
+    )]
+    file: Option<String>,
 
     #[arg(long, default_value_t = false)]
     debug: bool,
+
+    /// Start from a snapshot instead of a program
+    #[arg(long, value_name = "PATH")]
+    load_snapshot: Option<PathBuf>,
+
+    /// Save a snapshot when the program exits. In windowed mode, Ctrl+S and Ctrl+L save and
+    /// load snapshots here (or to the --load-snapshot file, or hack.snap).
+    #[arg(long, value_name = "PATH")]
+    save_snapshot: Option<PathBuf>,
+
+    /// Write a line per executed instruction to a file
+    #[arg(long, value_name = "PATH")]
+    trace: Option<PathBuf>,
+
+    /// Count executions per instruction, line and label, writing a report here. The
+    /// profiler and --trace both need the machine's one tracer, so they can't be combined.
+    #[arg(long, value_name = "PATH", conflicts_with = "trace")]
+    profile: Option<PathBuf>,
+
+    /// Also write the profile as folded stacks, for flame graph tools
+    #[arg(long, value_name = "PATH", requires = "profile")]
+    profile_folded: Option<PathBuf>,
+
+    /// Attach a character console, at 24577 or --console=ADDR. Its output is printed in
+    /// --quiet mode and shown beside the screen otherwise. The asm symbol CONSOLE is always
+    /// 24577, so programs using it need the default address.
+    #[arg(
+        long,
+        value_name = "ADDR",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "24577",
+        value_parser = parse_address
+    )]
+    console: Option<u16>,
+
+    /// Save the screen as a PNG or PBM image here when the program exits
+    #[arg(long, value_name = "PATH")]
+    capture: Option<PathBuf>,
+
+    /// Also capture every N frames of 100000 cycles, adding the cycle number to the name
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires_all = ["capture", "quiet"]
+    )]
+    capture_every: Option<u64>,
+
+    /// Also capture when the cycle count reaches each of these
+    #[arg(
+        long,
+        value_name = "CYCLES",
+        value_delimiter = ',',
+        requires_all = ["capture", "quiet"]
+    )]
+    capture_at: Vec<u64>,
+
+    /// Play keyboard input from a script instead of, or in windowed mode before, the
+    /// live keyboard. Frame numbers count tenths of a second spent running in the window,
+    /// or 100000-cycle frames in --quiet mode.
+    #[arg(long, value_name = "PATH")]
+    input: Option<PathBuf>,
+
+    /// Record the live keyboard as an input script when the window closes
+    #[arg(long, value_name = "PATH", conflicts_with = "quiet")]
+    record_input: Option<PathBuf>,
+
+    /// Draw the screen in this terminal instead of a window, with braille (the default) or
+    /// half-block characters. Ctrl+C quits.
+    #[arg(
+        long,
+        value_name = "STYLE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "braille",
+        value_parser = TerminalStyle::from_str,
+        conflicts_with = "quiet"
+    )]
+    terminal: Option<TerminalStyle>,
+
+    /// Read the keyboard layout from a keymap file rather than assuming a US keyboard
+    #[arg(long, value_name = "PATH", conflicts_with = "quiet")]
+    keymap: Option<PathBuf>,
+
+    /// Instructions to run a second, like 500k or 2M, or "unlimited". Ctrl+Up and Ctrl+Down
+    /// double and halve it, Ctrl+P pauses and Ctrl+N steps while paused.
+    #[arg(
+        long,
+        value_name = "RATE",
+        default_value = "unlimited",
+        value_parser = Clock::from_str,
+        conflicts_with = "quiet"
+    )]
+    clock: Clock,
+
+    /// Draw each screen pixel as an NxN block in the window
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(1..=8),
+        conflicts_with = "quiet"
+    )]
+    scale: u8,
+
+    /// The colour of black pixels in the window, as hex RRGGBB
+    #[arg(long, value_name = "COLOUR", value_parser = parse_colour, conflicts_with = "quiet")]
+    foreground: Option<u32>,
+
+    /// The colour of white pixels in the window, as hex RRGGBB
+    #[arg(long, value_name = "COLOUR", value_parser = parse_colour, conflicts_with = "quiet")]
+    background: Option<u32>,
+
+    /// How many times a second to redraw the screen
+    #[arg(long, value_name = "N", default_value_t = 60, conflicts_with = "quiet")]
+    fps: u32,
+
+    /// Give up after this many instructions, exiting with status 124
+    #[arg(long, value_name = "N", requires = "quiet")]
+    max_steps: Option<u64>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Step through a program in an interactive debugger
+    Debug {
+        /// Path to instruction file
+        file: String,
+    },
+    /// Compare two traces written by --trace, reporting where they first differ
+    TraceDiff { left: PathBuf, right: PathBuf },
+    /// Run nand2tetris CPU emulator test scripts (.tst), comparing their output with their
+    /// .cmp files
+    Test {
+        #[arg(required = true)]
+        scripts: Vec<PathBuf>,
+    },
+    /// Turn a .hack file back into asm that assembles to the same instructions
+    Disassemble {
+        file: PathBuf,
+        /// The asm the file was assembled from, to reuse its label names
+        #[arg(long, value_name = "ASM")]
+        source: Option<PathBuf>,
+        /// Where to write the asm, instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// The exit status when --max-steps runs out, as timeout(1) uses
+const STEP_LIMIT_EXIT_CODE: i32 = 124;
+
+/// How many of the hottest instructions the profile report lists
+const PROFILE_TOP: usize = 20;
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    HackWord::parse_number(text)
+        .map(HackWord::as_u16)
+        .map_err(|e| e.to_string())
+}
+
+fn is_asm(path: &Path) -> bool {
+    path.extension().and_then(OsStr::to_str) == Some("asm")
 }
 
 fn main() -> Res {
     let args = Args::parse();
 
-    let (instructions, _debug_info) = {
-        let path = Path::new(&args.file);
-        if path.extension().and_then(OsStr::to_str) == Some("asm") {
-            compile_file(path, args.debug)?
+    match &args.command {
+        Some(Command::Debug { file }) => return debug(Path::new(file)),
+        Some(Command::TraceDiff { left, right }) => return trace_diff(left, right),
+        Some(Command::Test { scripts }) => return test(scripts),
+        Some(Command::Disassemble {
+            file,
+            source,
+            output,
+        }) => return disassemble_file(file, source.as_deref(), output.as_deref()),
+        None => (),
+    }
+
+    let mut machine = Machine::new();
+    let debug_info = if let Some(snapshot) = &args.load_snapshot {
+        machine.load_snapshot_file(snapshot)?;
+        None
+    } else {
+        let (instructions, debug_info) = {
+            let path = Path::new(args.file.as_deref().unwrap_or_default());
+            if is_asm(path) {
+                compile_file(
+                    path,
+                    // the window's inspector names addresses with the debug info
+                    args.debug || args.trace.is_some() || args.profile.is_some() || !args.quiet,
+                )?
+            } else {
+                (read_instructions(path)?, None)
+            }
+        };
+        machine.load_instructions(instructions);
+        debug_info
+    };
+
+    if let Some(address) = args.console {
+        let console = Console::new(address);
+        if args.quiet {
+            machine.attach(console.with_echo(std::io::stdout()))?;
         } else {
-            (read_instructions(path)?, None)
+            machine.attach(console)?;
         }
-    };
+    }
 
-    let mut machine = Machine::new();
-    machine.load_instructions(instructions);
+    if let Some(trace) = &args.trace {
+        let out = BufWriter::new(File::create(trace)?);
+        machine.set_tracer(TraceWriter::new(out, debug_info.as_ref()));
+    }
+    if args.profile.is_some() {
+        machine.set_tracer(Profiler::new(debug_info.as_ref()));
+    }
 
+    let input = args
+        .input
+        .as_ref()
+        .map(InputScript::read_file)
+        .transpose()?;
     if !args.quiet {
-        run_io(machine)?;
+        let defaults = WindowStyle::default();
+        let mut config = IoConfig {
+            input,
+            record_input: args.record_input.clone(),
+            terminal: args.terminal,
+            clock: args.clock,
+            fps: args.fps,
+            window: WindowStyle {
+                scale: args.scale.into(),
+                foreground: args.foreground.unwrap_or(defaults.foreground),
+                background: args.background.unwrap_or(defaults.background),
+            },
+            debug: debug_info.clone(),
+            keymap: args
+                .keymap
+                .as_ref()
+                .map(KeyMap::read_file)
+                .transpose()?
+                .unwrap_or_default(),
+            ..IoConfig::default()
+        };
+        if let Some(path) = args.save_snapshot.as_ref().or(args.load_snapshot.as_ref()) {
+            config.snapshot_path = path.clone();
+        }
+        machine = run_io(machine, &config)?;
+        if let Some(path) = &args.capture {
+            ScreenImage::capture(&machine).save(path)?;
+        }
     } else {
-        machine.run()?;
+        let reason = if args.capture.is_some() || input.is_some() {
+            let every = args
+                .capture_every
+                .map(|frames| {
+                    frames
+                        .checked_mul(FRAME_CYCLES)
+                        .and_then(NonZeroU64::new)
+                        .ok_or_else(|| err("--capture-every is too many frames to count in cycles"))
+                })
+                .transpose()?;
+            let plan = args.capture.as_ref().map(|path| CapturePlan {
+                path: path.clone(),
+                every,
+                at: args.capture_at.iter().copied().collect(),
+            });
+            let player = input
+                .map(|script| InputPlayer::headless(&script, FRAME_CYCLES))
+                .transpose()?;
+            run_headless(&mut machine, args.max_steps, plan.as_ref(), player)?
+        } else if let Some(max_steps) = args.max_steps {
+            machine.run_with_limit(max_steps)
+        } else {
+            machine.run();
+            StopReason::Halted
+        };
+        if reason == StopReason::StepLimit {
+            let max_steps = args.max_steps.unwrap_or_default();
+            eprintln!("Stopped after {max_steps} steps at pc {}", machine.pc());
+            finish(machine, &args)?;
+            std::process::exit(STEP_LIMIT_EXIT_CODE);
+        }
     }
 
-    Ok(())
+    finish(machine, &args)
 }
 
-pub fn run_asm(asm: &str, machine: &mut Machine) -> Res {
-    use crate::asm::compile;
-    let (instructions, _) = compile(asm.lines().map(|x| x.into()).collect(), false)?;
+/// Writes the profile, trace and snapshot the arguments asked for
+fn finish(mut machine: Machine, args: &Args) -> Res {
+    if let Some(profiler) = machine.take_tracer_as::<Profiler>() {
+        if let Some(path) = &args.profile {
+            profiler.write_report(BufWriter::new(File::create(path)?), PROFILE_TOP)?;
+        }
+        if let Some(path) = &args.profile_folded {
+            profiler.write_folded(BufWriter::new(File::create(path)?))?;
+        }
+    }
+    if let Some(mut tracer) = machine.take_tracer() {
+        tracer.finish()?;
+    }
+    if let Some(path) = &args.save_snapshot {
+        machine.save_snapshot_file(path)?;
+    }
 
-    machine.load_instructions(instructions);
+    Ok(())
+}
 
-    machine.run()?;
+fn trace_diff(left: &Path, right: &Path) -> Res {
+    let open = |path| -> Res<_> { Ok(BufReader::new(File::open(path)?)) };
+    match diff_traces(open(left)?, open(right)?)? {
+        Some(divergence) => {
+            println!("{divergence}");
+            std::process::exit(1);
+        }
+        None => println!("Traces match"),
+    }
+    Ok(())
+}
 
+fn test(scripts: &[PathBuf]) -> Res {
+    let mut failed = false;
+    for script in scripts {
+        match run_test(script) {
+            Ok(()) => println!(
+                "{}: End of script - Comparison ended successfully",
+                script.display()
+            ),
+            Err(e) => {
+                println!("{}: {e}", script.display());
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+fn disassemble_file(path: &Path, source: Option<&Path>, output: Option<&Path>) -> Res {
+    let rom = read_instructions(path)?;
+    let debug = match source {
+        Some(source) => {
+            let (instructions, debug) = compile_file(source, true)?;
+            if instructions != rom {
+                return Err(format!(
+                    "{} doesn't assemble to {}",
+                    source.display(),
+                    path.display()
+                )
+                .into());
+            }
+            debug
+        }
+        None => None,
+    };
+    let mut text = disassemble(&rom, debug.as_ref())?.join("\n");
+    text.push('\n');
+    match output {
+        Some(output) => std::fs::write(output, text)?,
+        None => print!("{text}"),
+    }
+    Ok(())
+}
+
+fn debug(path: &Path) -> Res {
+    let (machine, debug, source) = if is_asm(path) {
+        let source = read_lines(path)?;
+        let (instructions, debug) = compile(source.clone(), true)?;
+        (Machine::from_instructions(instructions), debug, source)
+    } else {
+        let machine = Machine::from_instructions(read_instructions(path)?);
+        (machine, None, Vec::new())
+    };
+
+    let mut debugger = Debugger::new(machine, debug, source);
+    debugger.run(std::io::stdin().lock(), std::io::stdout().lock())
+}
@@ -0,0 +1,352 @@
+//! Counting where a program spends its cycles.
+//!
+//! [`Profiler`] is a [`Tracer`] that counts executions per ROM address. It maps them back to
+//! source lines and to the regions between labels, and records how often each jump is taken.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::{
+    asm::{AsmDebug, SourceIndex},
+    common::Res,
+    instruction::{Instruction, Jump},
+    machine::{TraceEvent, Tracer},
+};
+
+/// Where cycles before the first label are counted
+const UNLABELLED: &str = "(start)";
+
+#[derive(Clone, Copy, Default)]
+struct Site {
+    count: u64,
+    taken: u64,
+    instruction: Option<Instruction>,
+}
+
+/// A conditional or unconditional jump, and how often it went each way
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Branch {
+    pub pc: u16,
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+#[derive(Default)]
+pub struct Profiler {
+    source: SourceIndex,
+    sites: Vec<Site>,
+    total: u64,
+}
+
+impl Tracer for Profiler {
+    fn trace(&mut self, event: &TraceEvent) {
+        let pc = event.pc as usize;
+        if self.sites.len() <= pc {
+            self.sites.resize(pc + 1, Site::default());
+        }
+        let site = &mut self.sites[pc];
+        site.count += 1;
+        site.instruction = Some(event.instruction);
+        if event.jumped {
+            site.taken += 1;
+        }
+        self.total += 1;
+    }
+}
+
+impl Profiler {
+    pub fn new(debug: Option<&AsmDebug>) -> Self {
+        Self {
+            source: SourceIndex::new(debug),
+            ..Self::default()
+        }
+    }
+
+    /// How many instructions have been profiled
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// How many times the instruction at `pc` ran
+    pub fn count(&self, pc: u16) -> u64 {
+        self.sites.get(pc as usize).map_or(0, |site| site.count)
+    }
+
+    /// Cycles per source line, for instructions with debug info
+    pub fn lines(&self) -> BTreeMap<usize, u64> {
+        let mut lines = BTreeMap::new();
+        for (pc, site) in self.executed() {
+            if let Some((line, _)) = self.source.line(pc) {
+                *lines.entry(line).or_default() += site.count;
+            }
+        }
+        lines
+    }
+
+    /// Cycles spent between each label and the next, hottest first
+    pub fn regions(&self) -> Vec<(String, u64)> {
+        let mut regions: BTreeMap<&str, u64> = BTreeMap::new();
+        for (pc, site) in self.executed() {
+            *regions.entry(self.region(pc)).or_default() += site.count;
+        }
+        let mut regions: Vec<_> = regions
+            .into_iter()
+            .map(|(label, count)| (label.to_string(), count))
+            .collect();
+        regions.sort_by(|(a, x), (b, y)| y.cmp(x).then(a.cmp(b)));
+        regions
+    }
+
+    /// Every jump that ran, in ROM order
+    pub fn branches(&self) -> Vec<Branch> {
+        self.executed()
+            .filter(|(_, site)| {
+                matches!(site.instruction, Some(Instruction::C { jump, .. }) if jump != Jump::Null)
+            })
+            .map(|(pc, site)| Branch {
+                pc,
+                taken: site.taken,
+                not_taken: site.count - site.taken,
+            })
+            .collect()
+    }
+
+    /// Writes the hottest `top` lines (or ROM addresses, without debug info), the cycles per
+    /// label and the branch statistics
+    pub fn write_report(&self, mut out: impl Write, top: usize) -> Res {
+        writeln!(out, "{} cycles", self.total)?;
+
+        let mut hottest: Vec<(String, String, u64)> = if self.lines().is_empty() {
+            self.executed()
+                .map(|(pc, site)| (format!("pc {pc}"), self.text(pc), site.count))
+                .collect()
+        } else {
+            let pcs = self.line_pcs();
+            self.lines()
+                .into_iter()
+                .map(|(line, count)| (format!("line {line}"), self.text(pcs[&line]), count))
+                .collect()
+        };
+        hottest.sort_by(|(_, _, x), (_, _, y)| y.cmp(x));
+        writeln!(out, "\nHottest instructions")?;
+        writeln!(
+            out,
+            "{:>12} {:>7}  {:<12} source",
+            "cycles", "%", "location"
+        )?;
+        for (location, text, count) in hottest.into_iter().take(top) {
+            let percent = self.percent(count);
+            writeln!(out, "{count:>12} {percent:>6.2}%  {location:<12} {text}")?;
+        }
+
+        writeln!(out, "\nCycles per label")?;
+        writeln!(out, "{:>12} {:>7}  label", "cycles", "%")?;
+        for (label, count) in self.regions() {
+            let percent = self.percent(count);
+            writeln!(out, "{count:>12} {percent:>6.2}%  {label}")?;
+        }
+
+        writeln!(out, "\nBranches")?;
+        writeln!(
+            out,
+            "{:>12} {:>12} {:>7}  {:<12} source",
+            "taken", "not taken", "taken%", "location"
+        )?;
+        for branch in self.branches() {
+            let ratio = 100.0 * branch.taken as f64 / (branch.taken + branch.not_taken) as f64;
+            writeln!(
+                out,
+                "{:>12} {:>12} {ratio:>6.2}%  {:<12} {}",
+                branch.taken,
+                branch.not_taken,
+                self.location(branch.pc),
+                self.text(branch.pc)
+            )?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Writes `label;location count` lines, the folded stack format flame graph tools read
+    pub fn write_folded(&self, mut out: impl Write) -> Res {
+        let mut folded: BTreeMap<(&str, String), u64> = BTreeMap::new();
+        for (pc, site) in self.executed() {
+            *folded
+                .entry((self.region(pc), self.location(pc)))
+                .or_default() += site.count;
+        }
+        for ((region, location), count) in folded {
+            writeln!(out, "{region};{location} {count}")?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    fn executed(&self) -> impl Iterator<Item = (u16, &Site)> {
+        (0..).zip(&self.sites).filter(|(_, site)| site.count > 0)
+    }
+
+    fn region(&self, pc: u16) -> &str {
+        self.source.label(pc).map_or(UNLABELLED, |(label, _)| label)
+    }
+
+    fn location(&self, pc: u16) -> String {
+        match self.source.line(pc) {
+            Some((line, _)) => format!("line {line}"),
+            None => format!("pc {pc}"),
+        }
+    }
+
+    /// The source text of an instruction, or its disassembly
+    fn text(&self, pc: u16) -> String {
+        match (self.source.line(pc), self.sites[pc as usize].instruction) {
+            (Some((_, text)), _) => text.to_string(),
+            (None, Some(instruction)) => instruction.to_string(),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// The first executed ROM address on each source line
+    fn line_pcs(&self) -> BTreeMap<usize, u16> {
+        let mut pcs = BTreeMap::new();
+        for (pc, _) in self.executed() {
+            if let Some((line, _)) = self.source.line(pc) {
+                pcs.entry(line).or_insert(pc);
+            }
+        }
+        pcs
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        100.0 * count as f64 / self.total.max(1) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asm::{compile_file, compile_lines},
+        hackword::HackWord,
+        machine::{Machine, ROM_SIZE},
+    };
+
+    fn profile_mult(a: i16, b: i16, debug: bool) -> Profiler {
+        let (instructions, info) = compile_file("resources/mult.asm", debug).unwrap();
+        let mut machine = Machine::from_instructions(instructions);
+        machine.memory[0] = HackWord(a);
+        machine.memory[1] = HackWord(b);
+        machine.set_tracer(Profiler::new(info.as_ref()));
+        machine.run();
+
+        let profiler = machine.take_tracer_as::<Profiler>().unwrap();
+        assert_eq!(profiler.total(), machine.cycles());
+        profiler
+    }
+
+    #[test]
+    fn counts_lines_and_regions() {
+        let profiler = profile_mult(3, 5, true);
+
+        // `@R1` at the top of the loop runs once per iteration, plus the final check
+        assert_eq!(profiler.count(4), 6);
+        assert_eq!(profiler.lines()[&10], 6);
+        assert_eq!(profiler.lines().get(&8), None);
+
+        let regions = profiler.regions();
+        assert_eq!(regions[0], ("LOOP".to_string(), 5 * 11 + 5));
+        assert_eq!(regions[1], ("(start)".to_string(), 4));
+        assert_eq!(
+            regions.iter().map(|(_, c)| c).sum::<u64>(),
+            profiler.total()
+        );
+    }
+
+    #[test]
+    fn branch_ratios() {
+        let profiler = profile_mult(3, 5, true);
+
+        assert_eq!(
+            profiler.branches(),
+            [
+                // `D;JEQ` skipping the loop when R0 is 0
+                Branch {
+                    pc: 3,
+                    taken: 0,
+                    not_taken: 1,
+                },
+                // `D;JEQ` leaving the loop
+                Branch {
+                    pc: 8,
+                    taken: 1,
+                    not_taken: 5,
+                },
+                // `0;JMP` back to the top
+                Branch {
+                    pc: 14,
+                    taken: 5,
+                    not_taken: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn taken_means_jumped() {
+        // a jump to the next instruction is still taken
+        let mut machine = Machine::from_instructions(compile_lines("@2\n0;JMP\nD=1").unwrap());
+        machine.set_tracer(Profiler::new(None));
+        machine.run();
+        let profiler = machine.take_tracer_as::<Profiler>().unwrap();
+        assert_eq!(profiler.branches()[0].taken, 1);
+
+        // and running off the end of ROM back to 0 isn't
+        let mut rom = vec![HackWord::zero(); ROM_SIZE - 1];
+        rom.extend(compile_lines("D;JGT").unwrap());
+        let mut machine = Machine::from_instructions(rom);
+        machine.set_tracer(Profiler::new(None));
+        machine.run_with_limit(ROM_SIZE as u64 + 1);
+        let profiler = machine.take_tracer_as::<Profiler>().unwrap();
+        assert_eq!(
+            profiler.branches(),
+            [Branch {
+                pc: ROM_SIZE as u16 - 1,
+                taken: 0,
+                not_taken: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn reports() {
+        let profiler = profile_mult(3, 5, true);
+        let mut report = Vec::new();
+        profiler.write_report(&mut report, 3).unwrap();
+        let report = String::from_utf8(report).unwrap();
+
+        assert!(report.starts_with("64 cycles\n"), "{report}");
+        assert!(report.contains("  line 10      @R1"), "{report}");
+        assert!(report.contains("  LOOP\n"), "{report}");
+        assert!(
+            report.contains("           1            5  16.67%  line 14      D;JEQ"),
+            "{report}"
+        );
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        assert!(folded.contains("(start);line 3 1\n"), "{folded}");
+        assert!(folded.contains("LOOP;line 10 6\n"), "{folded}");
+    }
+
+    #[test]
+    fn without_debug_info() {
+        let profiler = profile_mult(3, 5, false);
+        let mut report = Vec::new();
+        profiler.write_report(&mut report, 100).unwrap();
+        let report = String::from_utf8(report).unwrap();
+
+        assert!(report.contains("  pc 4         @1"), "{report}");
+        assert_eq!(profiler.regions(), [("(start)".to_string(), 64)]);
+    }
+}
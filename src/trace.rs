@@ -0,0 +1,243 @@
+//! A line-per-instruction text trace, and comparing two traces.
+//!
+//! Each line has space-separated `key=value` fields, then optionally ` ; ` and the source
+//! line and nearest label when the program was assembled with debug info:
+//!
+//! ```text
+//! cycle=4 pc=4 A=15->1 D=3->3 write=- ins=@1 ; line 10 LOOP
+//! cycle=5 pc=5 A=1->1 D=3->2 write=- ins=D=M ; line 11 LOOP+1
+//! cycle=6 pc=6 A=1->1 D=2->2 write=RAM[1]:2->1 ins=M=M-1 ; line 12 LOOP+2
+//! ```
+//!
+//! Only the fields are compared when diffing, so a trace of a program built without debug
+//! info can be compared with one built with it.
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use crate::{
+    asm::{AsmDebug, SourceIndex},
+    common::Res,
+    machine::{TraceEvent, Tracer},
+};
+
+const ANNOTATION: &str = " ; ";
+
+/// A [`Tracer`] writing one line per instruction
+pub struct TraceWriter<W: Write> {
+    out: W,
+    source: SourceIndex,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(out: W, debug: Option<&AsmDebug>) -> Self {
+        Self {
+            out,
+            source: SourceIndex::new(debug),
+            error: None,
+        }
+    }
+
+    pub fn format(&self, event: &TraceEvent) -> String {
+        let mut line = format!(
+            "cycle={} pc={} A={}->{} D={}->{} write=",
+            event.cycle, event.pc, event.a_before, event.a_after, event.d_before, event.d_after
+        );
+        match event.write {
+            Some(write) => line += &format!("RAM[{}]:{}->{}", write.address, write.old, write.new),
+            None => line.push('-'),
+        }
+        line += &format!(" ins={}", event.instruction);
+
+        if let Some((source, _)) = self.source.line(event.pc) {
+            line += &format!("{ANNOTATION}line {source}");
+            match self.source.label(event.pc) {
+                Some((label, 0)) => line += &format!(" {label}"),
+                Some((label, offset)) => line += &format!(" {label}+{offset}"),
+                None => (),
+            }
+        }
+        line
+    }
+}
+
+impl<W: Write + 'static> Tracer for TraceWriter<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        if self.error.is_none() {
+            let line = self.format(event);
+            if let Err(e) = writeln!(self.out, "{line}") {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Res {
+        if let Some(e) = self.error.take() {
+            return Err(e.into());
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// The first difference between two traces
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Divergence {
+    /// The 1-based line the traces differ on
+    pub line: usize,
+    /// The first field that differs, or `None` if one trace ended early
+    pub field: Option<String>,
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.field {
+            Some(field) => writeln!(f, "Traces diverge at line {} in {field}:", self.line)?,
+            None => writeln!(f, "Traces diverge at line {}, where one ends:", self.line)?,
+        }
+        let end = "<end of trace>".to_string();
+        writeln!(f, "< {}", self.left.as_ref().unwrap_or(&end))?;
+        write!(f, "> {}", self.right.as_ref().unwrap_or(&end))
+    }
+}
+
+/// Compares two traces field by field, ignoring source annotations
+pub fn diff_traces(left: impl BufRead, right: impl BufRead) -> Res<Option<Divergence>> {
+    let mut left = left.lines();
+    let mut right = right.lines();
+    for line in 1.. {
+        let (l, r) = match (left.next().transpose()?, right.next().transpose()?) {
+            (None, None) => return Ok(None),
+            (l, r) => (l, r),
+        };
+        let field = match (&l, &r) {
+            (Some(l), Some(r)) => match first_difference(l, r) {
+                Some(field) => Some(field),
+                None => continue,
+            },
+            _ => None,
+        };
+        return Ok(Some(Divergence {
+            line,
+            field,
+            left: l,
+            right: r,
+        }));
+    }
+    unreachable!()
+}
+
+/// The key of the first field that differs between two trace lines
+fn first_difference(left: &str, right: &str) -> Option<String> {
+    let fields = |line: &str| {
+        let line = line
+            .split_once(ANNOTATION)
+            .map_or(line, |(fields, _)| fields);
+        line.split_whitespace()
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    let (left, right) = (fields(left), fields(right));
+    if left == right {
+        return None;
+    }
+    let key = |field: &String| {
+        field
+            .split_once('=')
+            .map_or(field.clone(), |(k, _)| k.into())
+    };
+    Some(
+        left.iter()
+            .zip(&right)
+            .find(|(l, r)| l != r)
+            .map_or_else(|| "the number of fields".into(), |(l, _)| key(l)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{asm::compile_file, hackword::HackWord, machine::Machine};
+
+    /// A writer the test can read back after handing it to the machine
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(r0: i16, debug: bool) -> String {
+        let (instructions, info) = compile_file("resources/mult.asm", debug).unwrap();
+        let mut machine = Machine::from_instructions(instructions);
+        machine.memory[0] = HackWord(r0);
+        machine.memory[1] = HackWord(2);
+
+        let output = Shared::default();
+        machine.set_tracer(TraceWriter::new(output.clone(), info.as_ref()));
+        machine.run();
+        machine.take_tracer().unwrap().finish().unwrap();
+
+        let bytes = output.0.lock().unwrap().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn lines_show_state_and_source() {
+        let output = trace(3, true);
+        let lines: Vec<_> = output.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "cycle=0 pc=0 A=0->0 D=0->0 write=- ins=@0 ; line 3"
+        );
+        assert_eq!(
+            lines[6],
+            "cycle=6 pc=6 A=1->1 D=2->2 write=RAM[1]:2->1 ins=M=M-1 ; line 12 LOOP+2"
+        );
+        assert!(lines[4].ends_with("ins=@1 ; line 10 LOOP"), "{}", lines[4]);
+    }
+
+    #[test]
+    fn diff_ignores_annotations() {
+        let with_debug = trace(3, true);
+        let without = trace(3, false);
+
+        assert!(!without.contains(ANNOTATION));
+        assert_eq!(
+            diff_traces(with_debug.as_bytes(), without.as_bytes()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn diff_finds_the_first_difference() {
+        let three = trace(3, false);
+        let four = trace(4, false);
+
+        let divergence = diff_traces(three.as_bytes(), four.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.line, 2);
+        assert_eq!(divergence.field.as_deref(), Some("D"));
+
+        let shorter = three.lines().take(5).collect::<Vec<_>>().join("\n");
+        let divergence = diff_traces(shorter.as_bytes(), three.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.line, 6);
+        assert_eq!(divergence.field, None);
+        assert_eq!(divergence.left, None);
+    }
+}
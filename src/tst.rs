@@ -0,0 +1,713 @@
+//! Running the nand2tetris CPU emulator's test scripts.
+//!
+//! A `.tst` script loads a program, sets up RAM and registers, runs some instructions and
+//! prints chosen values as a table to an `.out` file, checking each line against a `.cmp`
+//! file as it goes:
+//!
+//! ```text
+//! load Mult.asm,
+//! output-file Mult.out,
+//! compare-to Mult.cmp,
+//! output-list RAM[0]%D2.6.2 RAM[1]%D2.6.2 RAM[2]%D2.6.2;
+//!
+//! set RAM[0] 3, set RAM[1] 4;
+//! repeat 50 {
+//!   ticktock;
+//! }
+//! output;
+//! ```
+//!
+//! This covers the CPU emulator's commands: `load`, `output-file`, `compare-to`,
+//! `output-list`, `set`, `ticktock`, `output`, `repeat`, `while`, `echo` and `clear-echo`.
+//! The variables are `A`, `D`, `PC`, `RAM[n]`, `ROM[n]` and `time`, the instructions run.
+//! Like the hardware, `ticktock` always runs an instruction, going round halting loops and
+//! reading `@0` from the empty ROM past the program.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    asm::compile_file,
+    common::{err, read_lines, Res},
+    hackword::HackWord,
+    io::read_instructions,
+    machine::{Machine, MEMORY_SIZE},
+};
+
+/// The format of `output-list` entries that don't give one
+const DEFAULT_FORMAT: &str = "B1.16.1";
+
+/// Something a script can read, and set unless it's ROM or `time`
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Variable {
+    A,
+    D,
+    Pc,
+    Ram(u16),
+    Rom(u16),
+    Time,
+}
+
+impl Variable {
+    fn parse(name: &str) -> Res<Self> {
+        let indexed = |prefix: &str| -> Option<Res<u16>> {
+            let index = name.strip_prefix(prefix)?.strip_suffix(']')?;
+            Some(match index.parse::<u16>() {
+                Ok(index) if (index as usize) < MEMORY_SIZE => Ok(index),
+                _ => Err(format!("'{name}' is outside memory").into()),
+            })
+        };
+        Ok(match name {
+            "A" => Self::A,
+            "D" => Self::D,
+            "PC" => Self::Pc,
+            "time" => Self::Time,
+            _ => match (indexed("RAM["), indexed("ROM[")) {
+                (Some(index), _) => Self::Ram(index?),
+                (_, Some(index)) => Self::Rom(index?),
+                _ => return Err(format!("Unknown variable '{name}'").into()),
+            },
+        })
+    }
+
+    /// The variable's value, widened so `time` isn't cut to 16 bits
+    fn get(self, machine: &Machine) -> i64 {
+        match self {
+            Self::A => machine.register_a().0.into(),
+            Self::D => machine.register_d().0.into(),
+            Self::Pc => machine.pc().into(),
+            Self::Ram(address) => machine.memory[address as usize].0.into(),
+            Self::Rom(address) => machine
+                .rom()
+                .get(address as usize)
+                .map_or(0, |word| word.0.into()),
+            Self::Time => machine.cycles() as i64,
+        }
+    }
+
+    fn set(self, machine: &mut Machine, value: HackWord) -> Res {
+        match self {
+            Self::A => machine.set_register_a(value),
+            Self::D => machine.set_register_d(value),
+            Self::Pc => machine.set_pc(value.as_u16()),
+            Self::Ram(address) => machine.poke(address, value),
+            Self::Rom(_) | Self::Time => return Err(err("Only A, D, PC and RAM can be set")),
+        }
+        Ok(())
+    }
+}
+
+/// How a column of the output table shows its values
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Format {
+    Binary,
+    Decimal,
+    Hex,
+    /// Decimal, but aligned left
+    String,
+}
+
+/// An `output-list` entry, like `RAM[0]%D2.6.2`: a variable, a format letter, and the
+/// spaces before the value, its width and the spaces after it
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Column {
+    pub name: String,
+    pub variable: Variable,
+    pub format: Format,
+    pub left: usize,
+    pub width: usize,
+    pub right: usize,
+}
+
+impl Column {
+    pub fn parse(entry: &str) -> Res<Self> {
+        let (name, format) = entry.split_once('%').unwrap_or((entry, DEFAULT_FORMAT));
+        let invalid = || format!("Invalid output format '%{format}'");
+        let mut chars = format.chars();
+        let format_type = match chars.next() {
+            Some('B') => Format::Binary,
+            Some('D') => Format::Decimal,
+            Some('X') => Format::Hex,
+            Some('S') => Format::String,
+            _ => return Err(invalid().into()),
+        };
+        let sizes: Vec<usize> = chars
+            .as_str()
+            .split('.')
+            .map(|n| n.parse().map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?;
+        let [left, width, right] = sizes[..] else {
+            return Err(invalid().into());
+        };
+        Ok(Self {
+            name: name.into(),
+            variable: Variable::parse(name)?,
+            format: format_type,
+            left,
+            width,
+            right,
+        })
+    }
+
+    /// The column's name centred in its full width, cut short if it doesn't fit
+    pub fn header(&self) -> String {
+        let total = self.left + self.width + self.right;
+        let name: String = self.name.chars().take(total).collect();
+        let before = (total - name.len()) / 2;
+        let after = total - name.len() - before;
+        format!("{}{name}{}", " ".repeat(before), " ".repeat(after))
+    }
+
+    pub fn cell(&self, value: i64) -> String {
+        let (left, width, right) = (self.left, self.width, self.right);
+        let bits = value as u16;
+        let text = match self.format {
+            Format::Binary => {
+                let digits = format!("{bits:016b}");
+                digits[16 - width.min(16)..].to_string()
+            }
+            Format::Hex => format!("{bits:0width$X}"),
+            Format::Decimal | Format::String => value.to_string(),
+        };
+        let pad = |n| " ".repeat(n);
+        match self.format {
+            Format::String => format!("{}{text:<width$}{}", pad(left), pad(right)),
+            _ => format!("{}{text:>width$}{}", pad(left), pad(right)),
+        }
+    }
+}
+
+/// A `while` loop's test: a variable compared with a constant
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Condition {
+    pub variable: Variable,
+    pub op: Comparison,
+    pub value: i64,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Condition {
+    fn holds(&self, machine: &Machine) -> bool {
+        let (left, right) = (self.variable.get(machine), self.value);
+        match self.op {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Command {
+    Load(PathBuf),
+    OutputFile(PathBuf),
+    CompareTo(PathBuf),
+    OutputList(Vec<Column>),
+    Set(Variable, HackWord),
+    TickTock,
+    Output,
+    Echo(String),
+    ClearEcho,
+    Repeat(u64, Vec<Statement>),
+    While(Condition, Vec<Statement>),
+}
+
+/// A command and the line of the script it starts on
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Statement {
+    pub line: usize,
+    pub command: Command,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+enum Token {
+    Word(String),
+    Text(String),
+    /// `,` or `;`, which end a command
+    End,
+    Open,
+    Close,
+}
+
+/// Splits a script into tokens and the lines they're on, dropping comments
+fn tokenize(text: &str) -> Res<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            ',' | ';' => Token::End,
+            '{' => Token::Open,
+            '}' => Token::Close,
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+                continue;
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if last == '*' => break,
+                        Some(c) => {
+                            line += (c == '\n') as usize;
+                            last = c;
+                        }
+                        None => return Err(format!("Line {line}: Unclosed comment").into()),
+                    }
+                }
+                continue;
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\n') | None => {
+                            return Err(format!("Line {line}: Unclosed string").into())
+                        }
+                        Some(c) => text.push(c),
+                    }
+                }
+                Token::Text(text)
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && !",;{}\"".contains(c))
+                {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+        };
+        tokens.push((line, token));
+    }
+    Ok(tokens)
+}
+
+/// A parsed `.tst` script
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct TestScript {
+    pub statements: Vec<Statement>,
+}
+
+impl TestScript {
+    pub fn parse(text: &str) -> Res<Self> {
+        let mut tokens = tokenize(text)?.into_iter().peekable();
+        let statements = parse_block(&mut tokens, None)?;
+        Ok(Self { statements })
+    }
+
+    pub fn read_file(path: impl AsRef<Path>) -> Res<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+}
+
+type Tokens = std::iter::Peekable<std::vec::IntoIter<(usize, Token)>>;
+
+/// Parses commands until the end of the script, or the `}` closing a block opened on
+/// `opened`
+fn parse_block(tokens: &mut Tokens, opened: Option<usize>) -> Res<Vec<Statement>> {
+    let mut statements = Vec::new();
+    loop {
+        let Some((line, token)) = tokens.next() else {
+            return match opened {
+                Some(line) => Err(format!("Line {line}: Unclosed block").into()),
+                None => Ok(statements),
+            };
+        };
+        let word = match token {
+            Token::End => continue,
+            Token::Close if opened.is_some() => return Ok(statements),
+            Token::Word(word) => word,
+            _ => return Err(format!("Line {line}: Expected a command").into()),
+        };
+
+        // the rest of the command, up to the token that ends it
+        let mut args = Vec::new();
+        let end = loop {
+            match tokens.next() {
+                Some((_, Token::Word(arg) | Token::Text(arg))) => args.push(arg),
+                Some((_, Token::Close)) if opened.is_some() => break Token::Close,
+                Some((_, end)) => break end,
+                None => break Token::End,
+            }
+        };
+        let command = match end {
+            Token::Open => {
+                let body = parse_block(tokens, Some(line))?;
+                parse_loop(&word, &args, body)
+            }
+            _ => parse_command(&word, &args),
+        }
+        .map_err(|e| format!("Line {line}: {e}"))?;
+        statements.push(Statement { line, command });
+        if end == Token::Close {
+            return Ok(statements);
+        }
+    }
+}
+
+fn parse_loop(word: &str, args: &[String], body: Vec<Statement>) -> Res<Command> {
+    match (word, args) {
+        ("repeat", [count]) => Ok(Command::Repeat(
+            count
+                .parse()
+                .map_err(|_| format!("Invalid repeat count '{count}'"))?,
+            body,
+        )),
+        ("repeat", []) => Err(err("Endless repeats aren't supported")),
+        ("while", [variable, op, value]) => {
+            let op = match op.as_str() {
+                "=" => Comparison::Equal,
+                "<>" => Comparison::NotEqual,
+                "<" => Comparison::Less,
+                "<=" => Comparison::LessOrEqual,
+                ">" => Comparison::Greater,
+                ">=" => Comparison::GreaterOrEqual,
+                _ => return Err(format!("Unknown comparison '{op}'").into()),
+            };
+            let condition = Condition {
+                variable: Variable::parse(variable)?,
+                op,
+                value: parse_value(value)?.0.into(),
+            };
+            Ok(Command::While(condition, body))
+        }
+        _ => Err(format!("'{word}' can't start a block").into()),
+    }
+}
+
+fn parse_command(word: &str, args: &[String]) -> Res<Command> {
+    let path = || match args {
+        [path] => Ok(PathBuf::from(path)),
+        _ => Err(format!("{word} takes a file name")),
+    };
+    let none = |command| match args {
+        [] => Ok(command),
+        _ => Err(format!("{word} takes no arguments")),
+    };
+    Ok(match word {
+        "load" => Command::Load(path()?),
+        "output-file" => Command::OutputFile(path()?),
+        "compare-to" => Command::CompareTo(path()?),
+        "output-list" => {
+            Command::OutputList(args.iter().map(|a| Column::parse(a)).collect::<Res<_>>()?)
+        }
+        "set" => match args {
+            [variable, value] => Command::Set(Variable::parse(variable)?, parse_value(value)?),
+            _ => return Err(err("set takes a variable and a value")),
+        },
+        "ticktock" => none(Command::TickTock)?,
+        "output" => none(Command::Output)?,
+        "echo" => Command::Echo(args.join(" ")),
+        "clear-echo" => none(Command::ClearEcho)?,
+        "tick" | "tock" => return Err(err("The CPU emulator only runs whole ticktocks")),
+        _ => return Err(format!("Unknown command '{word}'").into()),
+    })
+}
+
+/// Parses a value to set: decimal, or prefixed with `%D`, `%X` or `%B`
+fn parse_value(text: &str) -> Res<HackWord> {
+    match text.get(..2) {
+        Some("%D") => HackWord::parse_number(&text[2..]),
+        Some("%X") => HackWord::parse_number(&format!("0x{}", &text[2..])),
+        Some("%B") => HackWord::parse_number(&format!("0b{}", &text[2..])),
+        _ => HackWord::parse_number(text),
+    }
+}
+
+/// Runs scripts against a machine, writing the output table and checking it
+pub struct TestRunner {
+    /// Where the script is, which its file names are relative to
+    dir: PathBuf,
+    machine: Machine,
+    columns: Vec<Column>,
+    output: Option<BufWriter<File>>,
+    compare: Option<Vec<String>>,
+    /// Lines of the table output so far
+    lines: usize,
+}
+
+impl TestRunner {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            machine: Machine::new(),
+            columns: Vec::new(),
+            output: None,
+            compare: None,
+            lines: 0,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// Runs a script, failing at the first output line that doesn't match the compare file
+    pub fn run(&mut self, script: &TestScript) -> Res {
+        let result = self.run_block(&script.statements);
+        if let Some(output) = &mut self.output {
+            output.flush()?;
+        }
+        result
+    }
+
+    fn run_block(&mut self, statements: &[Statement]) -> Res {
+        for statement in statements {
+            self.run_command(&statement.command)
+                .map_err(|e| format!("Line {}: {e}", statement.line))?;
+        }
+        Ok(())
+    }
+
+    fn run_command(&mut self, command: &Command) -> Res {
+        match command {
+            Command::Load(path) => {
+                let path = self.dir.join(path);
+                let instructions = match path.extension().and_then(|e| e.to_str()) {
+                    Some("asm") => compile_file(&path, false)?.0,
+                    _ => read_instructions(&path)?,
+                };
+                self.machine = Machine::from_instructions(instructions);
+            }
+            Command::OutputFile(path) => {
+                self.output = Some(BufWriter::new(File::create(self.dir.join(path))?));
+            }
+            Command::CompareTo(path) => self.compare = Some(read_lines(self.dir.join(path))?),
+            Command::OutputList(columns) => {
+                self.columns = columns.clone();
+                let header: Vec<String> = columns.iter().map(Column::header).collect();
+                self.write_line(&header)?;
+            }
+            Command::Set(variable, value) => variable.set(&mut self.machine, *value)?,
+            Command::TickTock => self.machine.tick(),
+            Command::Output => {
+                let cells: Vec<String> = self
+                    .columns
+                    .iter()
+                    .map(|column| column.cell(column.variable.get(&self.machine)))
+                    .collect();
+                self.write_line(&cells)?;
+            }
+            Command::Echo(text) => eprintln!("{text}"),
+            Command::ClearEcho => (),
+            Command::Repeat(count, body) => {
+                for _ in 0..*count {
+                    self.run_block(body)?;
+                }
+            }
+            Command::While(condition, body) => {
+                while condition.holds(&self.machine) {
+                    self.run_block(body)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a row of the table and checks it against the compare file
+    fn write_line(&mut self, cells: &[String]) -> Res {
+        let line = format!("|{}|", cells.join("|"));
+        if let Some(output) = &mut self.output {
+            writeln!(output, "{line}")?;
+        }
+        self.lines += 1;
+        if let Some(compare) = &self.compare {
+            let expected = compare
+                .get(self.lines - 1)
+                .map_or("", |line| line.trim_end());
+            if !lines_match(&line, expected) {
+                return Err(format!(
+                    "Comparison failure at line {}: expected '{expected}', got '{line}'",
+                    self.lines
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether an output line matches a compare file's, where `*` matches any character
+fn lines_match(line: &str, expected: &str) -> bool {
+    line.chars().count() == expected.chars().count()
+        && line
+            .chars()
+            .zip(expected.chars())
+            .all(|(c, e)| c == e || e == '*')
+}
+
+/// Runs the script at `path`, with its file names relative to its directory
+pub fn run_test(path: impl AsRef<Path>) -> Res {
+    let path = path.as_ref();
+    let script = TestScript::read_file(path)?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    TestRunner::new(dir).run(&script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{common::TempDir, devices::Console};
+
+    #[test]
+    fn parsing() {
+        let script = TestScript::parse(
+            "// a comment\nload Mult.hack, output-list RAM[0]%D2.6.2 A;\n\
+             /* a\nblock */ set RAM[1] %X1F,\nrepeat 3 {\n  ticktock;\n}\n\
+             while D <> -1 { ticktock } echo \"done now\";",
+        )
+        .unwrap();
+        let lines: Vec<usize> = script.statements.iter().map(|s| s.line).collect();
+        assert_eq!(lines, [2, 2, 4, 5, 8, 8]);
+
+        let commands: Vec<&Command> = script.statements.iter().map(|s| &s.command).collect();
+        assert_eq!(commands[0], &Command::Load("Mult.hack".into()));
+        let Command::OutputList(columns) = commands[1] else {
+            panic!("{commands:?}");
+        };
+        assert_eq!(
+            (columns[0].left, columns[0].width, columns[0].right),
+            (2, 6, 2)
+        );
+        assert_eq!(columns[1].format, Format::Binary);
+        assert_eq!(commands[2], &Command::Set(Variable::Ram(1), HackWord(31)));
+        let tick = vec![Statement {
+            line: 6,
+            command: Command::TickTock,
+        }];
+        assert_eq!(commands[3], &Command::Repeat(3, tick));
+        let Command::While(condition, _) = commands[4] else {
+            panic!("{commands:?}");
+        };
+        assert_eq!((condition.op, condition.value), (Comparison::NotEqual, -1));
+        assert_eq!(commands[5], &Command::Echo("done now".into()));
+
+        for (bad, line) in [
+            ("load a.hack,\nfrobnicate", 2),
+            ("set RAM[40000] 1", 1),
+            ("output-list A%Q1.2.3", 1),
+            ("\nrepeat 2 {\n ticktock;", 2),
+            ("tick;", 1),
+        ] {
+            let error = TestScript::parse(bad).unwrap_err().to_string();
+            assert!(error.starts_with(&format!("Line {line}:")), "{error}");
+        }
+    }
+
+    #[test]
+    fn table_format() {
+        let column = Column::parse("RAM[0]%D2.6.2").unwrap();
+        assert_eq!(column.header(), "  RAM[0]  ");
+        assert_eq!(column.cell(-5), "      -5  ");
+
+        let binary = Column::parse("D%B1.16.1").unwrap();
+        assert_eq!(binary.cell(-2), " 1111111111111110 ");
+        assert_eq!(Column::parse("A%X1.4.1").unwrap().cell(255), " 00FF ");
+        assert_eq!(Column::parse("time%S1.4.1").unwrap().cell(7), " 7    ");
+        assert_eq!(Column::parse("PC%D0.1.0").unwrap().header(), "P");
+        let default = Column::parse("A").unwrap();
+        assert_eq!((default.format, default.width), (Format::Binary, 16));
+
+        assert!(lines_match("|  3 |", "|  * |"));
+        assert!(!lines_match("|  3 |", "|  3|"));
+    }
+
+    #[test]
+    fn mult() {
+        // a copy, so the .out file isn't written among the sources
+        let dir = TempDir::new("tst-mult");
+        let dir = dir.path();
+        std::fs::create_dir_all(dir.join("tst")).unwrap();
+        std::fs::copy("resources/mult.asm", dir.join("mult.asm")).unwrap();
+        for file in ["Mult.tst", "Mult.cmp"] {
+            std::fs::copy(
+                Path::new("resources/tst").join(file),
+                dir.join("tst").join(file),
+            )
+            .unwrap();
+        }
+
+        run_test(dir.join("tst/Mult.tst")).unwrap();
+        let out = read_lines(dir.join("tst/Mult.out")).unwrap();
+        // mult.asm counts R1 down, so only the operand in R0 and the product are checked
+        assert_eq!(out.len(), 7);
+        assert!(out[6].starts_with("|       6  |"), "{}", out[6]);
+        assert!(out[6].ends_with("|      42  |"), "{}", out[6]);
+    }
+
+    #[test]
+    fn ticks_in_halting_loops() {
+        let dir = TempDir::new("tst-halt");
+        let dir = dir.path();
+        std::fs::write(dir.join("halt.asm"), "@7\nD=A\n(END)\n@END\n0;JMP\n").unwrap();
+        let script = TestScript::parse(
+            "load halt.asm, output-file halt.out,\n\
+             output-list time%D1.4.1 PC%D1.4.1 A%D1.4.1 D%D1.4.1;\n\
+             repeat 60 { ticktock; } output;\n\
+             ticktock; output;\n\
+             while time < 100 { ticktock; } output;",
+        )
+        .unwrap();
+
+        TestRunner::new(dir).run(&script).unwrap();
+        let out = read_lines(dir.join("halt.out")).unwrap();
+        // the loop's instructions keep running, as they do on the hardware
+        assert_eq!(
+            out,
+            [
+                "| time |  PC  |  A   |  D   |",
+                "|   60 |    2 |    2 |    7 |",
+                "|   61 |    3 |    2 |    7 |",
+                "|  100 |    2 |    2 |    7 |",
+            ]
+        );
+    }
+
+    #[test]
+    fn setting_ram_goes_through_devices() {
+        let script = TestScript::parse("set RAM[24577] 104, set RAM[24577] 105;").unwrap();
+        let mut runner = TestRunner::new(".");
+        runner.machine.attach(Console::default()).unwrap();
+
+        runner.run(&script).unwrap();
+        assert_eq!(runner.machine().device::<Console>().unwrap().text(), "hi");
+    }
+
+    #[test]
+    fn comparison_failures() {
+        let dir = TempDir::new("tst");
+        let dir = dir.path();
+        std::fs::write(dir.join("wrong.cmp"), "|  RAM[0]  |\n|       7  |\n").unwrap();
+        let script = TestScript::parse(
+            "compare-to wrong.cmp,\noutput-list RAM[0]%D2.6.2;\nset RAM[0] 6;\noutput;",
+        )
+        .unwrap();
+
+        let error = TestRunner::new(dir).run(&script).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Line 4: Comparison failure at line 2: expected '|       7  |', got '|       6  |'"
+        );
+    }
+}